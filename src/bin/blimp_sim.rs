@@ -0,0 +1,91 @@
+// Runs BlimpMainAlgo against the simulated airframe from a scenario file and writes the trace.
+// Exits with failure if any of the scenario's expectations weren't met.
+//
+// Usage: blimp_sim <scenario.json> [--format csv|json] [--output <file>]
+// Built only with the sim feature: cargo run --features sim --bin blimp_sim -- <args>
+
+use blimp_onboard_software::obsw_scenario::*;
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: blimp_sim <scenario.json> [--format csv|json] [--output <file>]";
+
+struct Args {
+    scenario: PathBuf,
+    json: bool,
+    output: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut scenario = None;
+    let mut json = false;
+    let mut output = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => match args.next().as_deref() {
+                Some("csv") => json = false,
+                Some("json") => json = true,
+                other => return Err(format!("Unknown format {other:?}")),
+            },
+            "--output" => {
+                output = Some(PathBuf::from(args.next().ok_or("Missing output file")?));
+            }
+            _ if scenario.is_none() && !arg.starts_with("--") => {
+                scenario = Some(PathBuf::from(arg));
+            }
+            _ => return Err(format!("Unexpected argument {arg}")),
+        }
+    }
+    Ok(Args {
+        scenario: scenario.ok_or("Missing scenario file")?,
+        json,
+        output,
+    })
+}
+
+fn run(args: &Args) -> Result<bool, String> {
+    let scenario = Scenario::load(&args.scenario)?;
+    let result = run_scenario(&scenario)?;
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .map_err(|e| format!("Can't create {}: {e}", path.display()))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let written = if args.json {
+        write_json(&result.trace, &mut out)
+    } else {
+        write_csv(&result.trace, &mut out)
+    };
+    written
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("Can't write trace: {e}"))?;
+
+    for failure in &result.failures {
+        eprintln!("FAILED {failure}");
+    }
+    Ok(result.passed())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(2)
+        }
+    }
+}
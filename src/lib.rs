@@ -1,5 +1,19 @@
+pub mod obsw_actuator;
 pub mod obsw_algo;
+pub mod obsw_attitude;
+pub mod obsw_auth;
+pub mod obsw_estimator;
+pub mod obsw_failsafe;
+pub mod obsw_framing;
 pub mod obsw_interface;
+pub mod obsw_link;
+pub mod obsw_mixer;
+pub mod obsw_pid;
+pub mod obsw_runtime;
+#[cfg(feature = "sim")]
+pub mod obsw_scenario;
+#[cfg(feature = "sim")]
+pub mod obsw_sim;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
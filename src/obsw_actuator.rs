@@ -0,0 +1,178 @@
+use crate::obsw_mixer::OutputKind;
+
+use std::collections::HashMap;
+
+// Conditioning of one actuator channel's command before it reaches hardware
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ActuatorConfig {
+    pub min: f64,
+    pub max: f64,
+    pub trim: f64,              // Added to command, e.g. servo center offset
+    pub reverse: bool,          // Negate command (before trim)
+    pub deadband: f64,          // Commands smaller than this in magnitude are treated as zero
+    pub slew_rate: Option<f64>, // Maximum output change per second
+}
+
+impl ActuatorConfig {
+    pub fn full_range(kind: OutputKind) -> Self {
+        let (min, max) = match kind {
+            OutputKind::Motor => (i32::MIN as f64, i32::MAX as f64),
+            OutputKind::Servo => (i16::MIN as f64, i16::MAX as f64),
+        };
+        Self {
+            min,
+            max,
+            trim: 0.0,
+            reverse: false,
+            deadband: 0.0,
+            slew_rate: None,
+        }
+    }
+
+    pub fn validate(&self, kind: OutputKind) -> Result<(), String> {
+        let range = Self::full_range(kind);
+        let values = [self.min, self.max, self.trim, self.deadband];
+        if values.iter().any(|v| !v.is_finite()) {
+            return Err("Non-finite actuator parameter".into());
+        }
+        if self.min > self.max {
+            return Err(format!("Minimum {} above maximum {}", self.min, self.max));
+        }
+        if self.min < range.min || self.max > range.max {
+            return Err(format!(
+                "Limits exceed output range {}..{}",
+                range.min, range.max
+            ));
+        }
+        if self.trim < self.min || self.trim > self.max {
+            return Err(format!("Trim {} outside limits", self.trim));
+        }
+        if self.deadband < 0.0 {
+            return Err(format!("Negative deadband {}", self.deadband));
+        }
+        if let Some(rate) = self.slew_rate {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(format!("Invalid slew rate {rate}"));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns conditioned value and whether it had to be saturated
+    pub fn apply(&self, command: f64) -> (f64, bool) {
+        let command = if command.abs() < self.deadband {
+            0.0
+        } else {
+            command
+        };
+        let command = if self.reverse { -command } else { command };
+        let value = command + self.trim;
+        let clamped = value.clamp(self.min, self.max);
+        (clamped, clamped != value)
+    }
+}
+
+// Per-channel actuator configurations, channels not configured use the full output range
+#[derive(Clone, Debug, Default)]
+pub struct Actuators {
+    configs: HashMap<(OutputKind, u8), ActuatorConfig>,
+    outputs: HashMap<(OutputKind, u8), f64>, // Last values sent, for slew rate limiting
+}
+
+impl Actuators {
+    pub fn config(&self, kind: OutputKind, channel: u8) -> ActuatorConfig {
+        self.configs
+            .get(&(kind, channel))
+            .cloned()
+            .unwrap_or_else(|| ActuatorConfig::full_range(kind))
+    }
+
+    pub fn set_config(
+        &mut self,
+        kind: OutputKind,
+        channel: u8,
+        config: ActuatorConfig,
+    ) -> Result<(), String> {
+        config.validate(kind)?;
+        self.configs.insert((kind, channel), config);
+        Ok(())
+    }
+
+    // Conditions command for the channel, moving at most slew rate * dt from the last output.
+    // Returns output value and whether the command had to be saturated.
+    pub fn apply(&mut self, kind: OutputKind, channel: u8, command: f64, dt: f64) -> (f64, bool) {
+        let config = self.config(kind, channel);
+        let (target, saturated) = config.apply(command);
+        let value = match (self.outputs.get(&(kind, channel)), config.slew_rate) {
+            (Some(&prev), Some(rate)) => prev + (target - prev).clamp(-rate * dt, rate * dt),
+            _ => target,
+        };
+        self.outputs.insert((kind, channel), value);
+        (value, saturated)
+    }
+
+    // Sets output bypassing all conditioning, e.g. to cut motors immediately
+    pub fn force(&mut self, kind: OutputKind, channel: u8, value: f64) {
+        self.outputs.insert((kind, channel), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_deadband_reverse_trim_and_limits() {
+        let config = ActuatorConfig {
+            min: -100.0,
+            max: 100.0,
+            trim: 10.0,
+            reverse: true,
+            deadband: 5.0,
+            slew_rate: None,
+        };
+        assert_eq!(config.apply(3.0), (10.0, false));
+        assert_eq!(config.apply(50.0), (-40.0, false));
+        assert_eq!(config.apply(-200.0), (100.0, true));
+    }
+
+    #[test]
+    fn slew_rate_ramps_with_elapsed_time() {
+        let mut actuators = Actuators::default();
+        let config = ActuatorConfig {
+            slew_rate: Some(100.0),
+            ..ActuatorConfig::full_range(OutputKind::Motor)
+        };
+        actuators.set_config(OutputKind::Motor, 0, config).unwrap();
+        assert_eq!(
+            actuators.apply(OutputKind::Motor, 0, 0.0, 0.0),
+            (0.0, false)
+        );
+        assert_eq!(
+            actuators.apply(OutputKind::Motor, 0, 1000.0, 0.1),
+            (10.0, false)
+        );
+        assert_eq!(
+            actuators.apply(OutputKind::Motor, 0, 1000.0, 0.5),
+            (60.0, false)
+        );
+        actuators.force(OutputKind::Motor, 0, 0.0);
+        assert_eq!(
+            actuators.apply(OutputKind::Motor, 0, -1000.0, 0.2),
+            (-20.0, false)
+        );
+    }
+
+    #[test]
+    fn rejects_limits_outside_output_range() {
+        let mut actuators = Actuators::default();
+        let config = ActuatorConfig {
+            max: 40000.0,
+            ..ActuatorConfig::full_range(OutputKind::Servo)
+        };
+        assert!(actuators
+            .set_config(OutputKind::Servo, 0, config.clone())
+            .is_err());
+        assert!(actuators.set_config(OutputKind::Motor, 0, config).is_ok());
+    }
+}
@@ -1,9 +1,71 @@
+use crate::obsw_actuator::{ActuatorConfig, Actuators};
+use crate::obsw_attitude::{Attitude, AttitudeConfig, AttitudeEstimator};
+use crate::obsw_auth::MessageVerifier;
+use crate::obsw_estimator::{AltitudeEstimator, EstimatorConfig};
+use crate::obsw_failsafe::{Failsafe, FailsafeBehavior, FailsafeConfig, FailsafeState};
+use crate::obsw_framing::{encode_frame, FrameDecoder};
 use crate::obsw_interface::*;
+use crate::obsw_link::{
+    new_session_id, Acceptance, LinkMonitor, LinkMonitorConfig, LinkStats, Packet, SequenceWindow,
+};
+use crate::obsw_mixer::{Mixer, OutputKind};
+use crate::obsw_pid::{Pid, PidConfig};
 
-use postcard;
-use serde;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+// Default barometric reference - standard atmosphere at sea level (Pa, K)
+const STANDARD_PRESSURE: f64 = 101325.0;
+const STANDARD_TEMPERATURE: f64 = 288.15;
+// Stick inputs are expected to span roughly -CONTROL_LIMIT..=CONTROL_LIMIT
+const CONTROL_LIMIT: f64 = 1000.0;
+// How fast fully deflected sticks move the hold targets (m/s and rad/s per control unit)
+const ALTITUDE_NUDGE_RATE: f64 = 1.0 / CONTROL_LIMIT;
+const HEADING_NUDGE_RATE: f64 = 0.5 / CONTROL_LIMIT;
+// Altitude hold gains (control units per m, per m*s, per m/s)
+const ALTITUDE_KP: f64 = 200.0;
+const ALTITUDE_KI: f64 = 20.0;
+const ALTITUDE_KD: f64 = 300.0;
+// Heading hold gains (control units per rad, per rad/s)
+const HEADING_KP: f64 = 600.0;
+const HEADING_KD: f64 = 200.0;
+// GPS fix acceptance
+const GPS_FIX_TIMEOUT: f64 = 2.0; // s
+const GPS_MIN_SATELLITES: u8 = 4;
+const GPS_MAX_HDOP: f32 = 5.0;
+// Barometer is considered dead after this long without a sample (s)
+const BARO_SAMPLE_TIMEOUT: f64 = 1.0;
+// Accelerometer and magnetometer samples older than this aren't used (s)
+const IMU_SAMPLE_TIMEOUT: f64 = 0.5;
+const GRAVITY: f64 = 9.80665; // m/s^2
+
+// Periods of telemetry sent from step (s)
+const BARO_REFERENCE_REPORT_PERIOD: f64 = 1.0;
+const ATTITUDE_REPORT_PERIOD: f64 = 0.1;
+const ALTITUDE_REPORT_PERIOD: f64 = 0.2;
+
+// Return to launch - throttle per m of distance, its limit, and radius considered arrived (m)
+const RTL_THROTTLE_GAIN: f64 = 20.0;
+const RTL_MAX_THROTTLE: f64 = 0.5 * CONTROL_LIMIT;
+const RTL_ARRIVAL_RADIUS: f64 = 5.0;
+const EARTH_RADIUS: f64 = 6371000.0; // m
+
+// Version of MessageG2B/MessageB2G encoding, bump on any change to their layout. Peers talk
+// only to the same version, except for the uplink Packet envelope and Ping, Pong and Hello,
+// which must keep their variant indices and content in all versions from 7 on.
+pub const PROTOCOL_VERSION: u16 = 7;
+// Features of this software the ground station may rely on
+const CAPABILITIES: [&str; 7] = [
+    "altitude_hold",
+    "attitude_estimate",
+    "failsafe",
+    "return_to_launch",
+    "actuator_config",
+    "framing_cobs_crc16",
+    "uplink_hmac_sha256",
+];
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
 pub struct Controls {
     pub throttle: i32,
     pub elevation: i32,
@@ -20,9 +82,13 @@ pub enum BlimpAction {
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub enum SensorType {
     Barometer,
-    GPSLatitude,
-    GPSLongitude,
+    GPSLatitude,  // Ignored, positions come only as a whole in BlimpEvent::GpsFix
+    GPSLongitude, // Ignored, positions come only as a whole in BlimpEvent::GpsFix
     GPSAltitude,
+    Heading,       // Radians, clockwise from north
+    Gyroscope,     // rad/s, body frame forward-right-down
+    Accelerometer, // m/s^2, body frame forward-right-down, reads -g along z when level at rest
+    Magnetometer,  // Any units, body frame forward-right-down
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -30,9 +96,81 @@ pub enum BlimpEvent {
     Control(Controls),
     GetMsg(Vec<u8>),
     SensorDataF64(SensorType, f64),
+    GpsFix(GpsFix),
+    SensorDataVec3(SensorType, Vector3),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Vector3 {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Vector3 {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn norm(&self) -> f64 {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+
+    pub fn normalized(self) -> Option<Self> {
+        let norm = self.norm();
+        (norm > 0.0 && norm.is_finite()).then(|| self * (1.0 / norm))
+    }
+
+    pub fn cross(&self, other: Self) -> Self {
+        Self::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+}
+
+impl std::ops::Add for Vector3 {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl std::ops::Sub for Vector3 {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl std::ops::Mul<f64> for Vector3 {
+    type Output = Self;
+    fn mul(self, k: f64) -> Self {
+        Self::new(self.x * k, self.y * k, self.z * k)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum GpsFixQuality {
+    NoFix,
+    Fix2D, // Altitude is not valid
+    Fix3D,
+}
+
+// Single position solution as reported by GPS receiver
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct GpsFix {
+    pub latitude: f64,  // Degrees
+    pub longitude: f64, // Degrees
+    pub altitude: f64,  // m above mean sea level
+    pub quality: GpsFixQuality,
+    pub satellites: u8,
+    pub hdop: f32,
+    pub timestamp: u64, // ms since UNIX epoch, UTC
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 pub enum FlightMode {
     Manual,            // Throttle -> motors speed; Pitch -> motors pitch; Roll -> motors yaw
     StabilizeAttiAlti, // Maintain altitude and attitude/azimuth
@@ -43,6 +181,25 @@ pub enum MessageG2B {
     Ping(u32),
     Pong(u32),
     Control(Controls),
+    SetFlightMode(FlightMode),
+    SetBaroReference { pressure: f64, temperature: f64 }, // Pa, K
+    CalibrateAltitudeZero, // Use current pressure as reference, only allowed on ground
+    SetFailsafeConfig(FailsafeConfig),
+    Arm,
+    Disarm,
+    SetActuatorConfig(OutputKind, u8, ActuatorConfig), // Kind, channel, config
+    Hello(Hello), // Must be sent first, other commands are refused until versions match.
+                  // May be unsigned, then it's only answered so ground learns blimp's session.
+}
+
+impl MessageG2B {
+    // Commands that must not be lost, so ground sends them reliably
+    pub fn reliable(&self) -> bool {
+        !matches!(
+            self,
+            MessageG2B::Ping(_) | MessageG2B::Pong(_) | MessageG2B::Control(_)
+        )
+    }
 }
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
@@ -51,148 +208,1927 @@ pub enum MessageB2G {
     Pong(u32),
     ForwardAction(BlimpAction),
     ForwardEvent(BlimpEvent),
+    FlightModeAck(FlightMode),
+    FlightModeRejected(FlightMode, String), // Requested mode, reason
+    BaroReference {
+        pressure: f64,
+        temperature: f64,
+    },
+    BaroReferenceRejected(String),
+    AltitudeEstimate {
+        altitude: f64,       // m
+        vertical_speed: f64, // m/s
+        altitude_std: f64,   // m
+    },
+    Attitude(Attitude),
+    FailsafeStatus(FailsafeState),
+    FailsafeConfigAck(FailsafeConfig),
+    FailsafeConfigRejected(String),
+    ArmingState(bool), // Armed
+    ArmRejected(String),
+    ActuatorConfigAck(OutputKind, u8),
+    ActuatorConfigRejected(OutputKind, u8, String),
+    ActuatorSaturation(Vec<(OutputKind, u8)>), // Channels currently saturated, sent on change
+    FrameErrors(u64), // Total corrupt or undecodable uplink frames, sent on change
+    Hello(Hello),     // Reply to ground's Hello, its session is what uplink MACs are bound to
+    HelloRejected(String),
+    CommandRefused(String), // Command ignored because there was no compatible Hello
+    Ack(u32),               // Sequence number of reliable uplink packet received
+    AuthRejected(u64),      // Total uplink messages failing authentication, sent on change
+    LinkStats(LinkStats),   // Sent with each ping the blimp originates
+}
+
+// Handshake, identifying the software on each end of the link
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub build: String, // Software name and version
+    pub capabilities: Vec<String>,
+    // Chosen anew on each start of the sender. Ground's restarts packet numbering, blimp's
+    // binds uplink authentication to this boot.
+    pub session: u64,
+    pub next_counter: u32, // From blimp, where uplink authentication counter continues
+}
+
+impl Hello {
+    // Describes this software
+    pub fn current(session: u64) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).into(),
+            capabilities: CAPABILITIES.iter().map(|&c| c.into()).collect(),
+            session,
+            next_counter: 0,
+        }
+    }
+
+    pub fn compatible(&self) -> Result<(), String> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(format!(
+                "Protocol version {} not supported, expected {PROTOCOL_VERSION}",
+                self.protocol_version
+            ));
+        }
+        Ok(())
+    }
 }
 
 pub struct BlimpMainAlgo {
-    action_callback: Option<Box<dyn Fn(BlimpAction) -> () + Send>>,
+    pending: Vec<BlimpAction>, // Produced during current call, emitted at its end
     curr_flight_mode: FlightMode,
+    armed: bool,
     controls: Controls,
     altitude: Option<f64>,
-    gps_location: Option<(f64, f64)>,
+    vertical_speed: Option<f64>,
+    estimator: AltitudeEstimator,
+    last_estimate: Option<Duration>,
+    pressure: Option<f64>,
+    last_baro: Option<Duration>,
+    base_pressure: f64,
+    temperature: f64,
+    heading: Option<f64>, // From compass, used when attitude is unknown
+    attitude: Option<Attitude>,
+    attitude_estimator: AttitudeEstimator,
+    last_gyro: Option<Duration>,
+    last_accel: Option<(Vector3, Duration)>,
+    last_mag: Option<(Vector3, Duration)>,
+    gps_fix: Option<(GpsFix, Duration)>, // Last usable fix and when it was received
+    launch_location: Option<(f64, f64)>,
+    failsafe: Failsafe,
+    last_step: Option<Duration>,
+    last_reference_report: Option<Duration>,
+    last_attitude_report: Option<Duration>,
+    last_altitude_report: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    now: Duration, // Clock reading at start of current step or handle_event
+    hold: HoldState,
+    mixer: Mixer,
+    actuators: Actuators,
+    saturated: Vec<(OutputKind, u8)>,
+    decoder: FrameDecoder,
+    session: u64,                // Own, sent in Hello
+    uplink_session: Option<u64>, // Ground station's, from its last Hello
+    uplink_window: SequenceWindow,
+    newest_command: Option<u32>, // Sequence number of the newest reliable packet applied
+    link_monitor: LinkMonitor,
+    peer: Option<Hello>, // Ground station that completed the handshake
+    verifier: MessageVerifier,
+    frame_errors: u64,
+    reported_frame_errors: u64,
+    auth_rejected: u64,
+    reported_auth_rejected: u64,
+}
+
+// Targets and controllers of StabilizeAttiAlti
+struct HoldState {
+    target_altitude: Option<f64>,
+    target_heading: Option<f64>,
+    altitude_pid: Pid,
+    heading_pid: Pid,
+}
+
+impl HoldState {
+    fn new() -> Self {
+        Self {
+            target_altitude: None,
+            target_heading: None,
+            altitude_pid: Pid::new(PidConfig {
+                kp: ALTITUDE_KP,
+                ki: ALTITUDE_KI,
+                kd: ALTITUDE_KD,
+                output_min: -CONTROL_LIMIT,
+                output_max: CONTROL_LIMIT,
+                ..Default::default()
+            }),
+            heading_pid: Pid::new(PidConfig {
+                kp: HEADING_KP,
+                kd: HEADING_KD,
+                output_min: -CONTROL_LIMIT,
+                output_max: CONTROL_LIMIT,
+                wrap_period: Some(std::f64::consts::TAU),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.target_altitude = None;
+        self.target_heading = None;
+        self.altitude_pid.reset();
+        self.heading_pid.reset();
+    }
 }
 
 impl BlimpAlgorithm<BlimpEvent, BlimpAction> for BlimpMainAlgo {
-    fn handle_event(
-        &mut self,
-        ev: &BlimpEvent,
-    ) -> std::pin::Pin<Box<impl std::future::Future<Output = ()>>> {
+    fn handle_event<'a>(
+        &'a mut self,
+        ev: &'a BlimpEvent,
+        out: &'a mut (dyn ActionSink<BlimpAction> + Send),
+    ) -> BoxFuture<'a> {
         Box::pin(async move {
+            self.now = self.now.max(self.clock.now());
             match ev {
                 BlimpEvent::Control(ctrl) => {
                     self.controls = ctrl.clone();
                 }
+                // Broken readings must not reach the estimators
+                BlimpEvent::SensorDataF64(_, value) if !value.is_finite() => {}
+                BlimpEvent::SensorDataF64(SensorType::Barometer, press) if *press <= 0.0 => {}
                 BlimpEvent::SensorDataF64(SensorType::Barometer, press) => {
-                    // Compute altitude
-                    // See: https://en.wikipedia.org/wiki/Barometric_formula
-                    // p = p_b * exp(-g * M * h / R / T)
-                    // ln (p / p_b) = -g * M * h / R / T
-                    // h = (ln p - ln p_b) * (-R) * T / g / M
-                    // h = (ln p_b - ln p) * R * T / g / M
-                    // TODO: Stablize and smoothen
-                    // TODO: Allow changing base (sea level) pressure and temperature
-                    let base_pressure: f64 = 101325.0;
-                    let temperature: f64 = 288.15;
-                    let const_coef: f64 = 0.0292718; // R / g / M
-                    self.altitude =
-                        Some((base_pressure.ln() - press.ln()) * const_coef * temperature);
-                }
-                BlimpEvent::SensorDataF64(SensorType::GPSLatitude, latitude) => {
-                    self.gps_location =
-                        Some((*latitude, self.gps_location.unwrap_or((0.0, 0.0)).1));
-                }
-                BlimpEvent::SensorDataF64(SensorType::GPSLongitude, longitude) => {
-                    self.gps_location =
-                        Some((self.gps_location.unwrap_or((0.0, 0.0)).0, *longitude));
-                }
-                BlimpEvent::GetMsg(msg) => {
-                    if let Ok(msg_deserialized) = postcard::from_bytes::<MessageG2B>(msg) {
-                        match msg_deserialized {
-                            MessageG2B::Ping(id) => {
-                                self.action_callback.as_ref().map(|x| {
-                                    x(BlimpAction::SendMsg(
-                                        postcard::to_stdvec::<MessageB2G>(&MessageB2G::Pong(id))
-                                            .unwrap(),
-                                    ));
-                                });
+                    self.pressure = Some(*press);
+                    self.last_baro = Some(self.now);
+                    self.predict_estimate();
+                    self.estimator.update_baro(self.pressure_altitude(*press));
+                    self.sync_estimate();
+                }
+                BlimpEvent::SensorDataF64(SensorType::GPSAltitude, altitude) => {
+                    self.predict_estimate();
+                    self.estimator.update_gps(*altitude);
+                    self.sync_estimate();
+                }
+                BlimpEvent::SensorDataF64(SensorType::Heading, heading) => {
+                    self.heading = Some(*heading);
+                }
+                BlimpEvent::SensorDataF64(SensorType::GPSLatitude, _)
+                | BlimpEvent::SensorDataF64(SensorType::GPSLongitude, _) => {}
+                // Vector sensors reported as scalars are meaningless
+                BlimpEvent::SensorDataF64(..) => {}
+                BlimpEvent::GpsFix(fix) => {
+                    self.handle_gps_fix(fix);
+                }
+                BlimpEvent::SensorDataVec3(SensorType::Gyroscope, gyro) => {
+                    self.handle_gyro(*gyro);
+                }
+                BlimpEvent::SensorDataVec3(SensorType::Accelerometer, accel) => {
+                    self.last_accel = Some((*accel, self.now));
+                    // Integrate vertical acceleration at full accelerometer rate
+                    self.predict_estimate();
+                    self.sync_estimate();
+                }
+                BlimpEvent::SensorDataVec3(SensorType::Magnetometer, mag) => {
+                    self.last_mag = Some((*mag, self.now));
+                }
+                BlimpEvent::SensorDataVec3(..) => {}
+                BlimpEvent::GetMsg(bytes) => {
+                    for frame in self.decoder.push(bytes) {
+                        let Ok(payload) = frame else {
+                            self.frame_errors += 1;
+                            continue;
+                        };
+                        let payload = match self.verifier.open(&payload) {
+                            Ok(msg) => msg,
+                            Err(_) => {
+                                self.handle_unauthenticated(&payload);
+                                continue;
                             }
-                            MessageG2B::Pong(id) => {}
-                            MessageG2B::Control(ctrl) => {
-                                self.handle_event(&BlimpEvent::Control(ctrl)).await;
+                        };
+                        match postcard::from_bytes::<Packet<MessageG2B>>(&payload) {
+                            Ok(packet) => {
+                                self.handle_packet(packet);
+                                // Only a ground station past the handshake proves the link works
+                                if self.peer.is_some() {
+                                    self.failsafe.link_alive(self.now);
+                                    self.link_monitor.seen(self.now);
+                                }
                             }
+                            Err(_) => self.frame_errors += 1,
                         }
-                    } else {
-                        eprintln!("Error occurred while deseerializing message");
                     }
                 }
-                _ => {}
             }
-            if matches!(ev, BlimpEvent::SensorDataF64(..)) {
-                self.action_callback.as_ref().map(|x| {
-                    x(BlimpAction::SendMsg(
-                        postcard::to_stdvec::<MessageB2G>(&MessageB2G::ForwardEvent(ev.clone()))
-                            .unwrap(),
-                    ));
-                });
+            // High rate IMU data isn't forwarded, attitude estimate is sent at a limited rate
+            // from step instead
+            if matches!(ev, BlimpEvent::SensorDataF64(..) | BlimpEvent::GpsFix(..)) {
+                self.send_msg(&MessageB2G::ForwardEvent(ev.clone()));
             }
+            self.flush(out);
         })
     }
 
-    fn set_action_callback(&mut self, callback: Box<dyn Fn(BlimpAction) -> () + Send>) {
-        self.action_callback = Some(callback);
+    fn step<'a>(&'a mut self, out: &'a mut (dyn ActionSink<BlimpAction> + Send)) -> BoxFuture<'a> {
+        Box::pin(async move {
+            self.now = self.now.max(self.clock.now());
+            self.control();
+            self.flush(out);
+        })
     }
 }
 
 impl BlimpMainAlgo {
-    pub fn new() -> Self {
+    // Every uplink message must be signed with the key, which is shared with the ground station
+    pub fn new(auth_key: &[u8]) -> Self {
+        assert!(!auth_key.is_empty(), "Authentication key must not be empty");
+        let session = new_session_id();
         Self {
-            action_callback: None,
+            pending: Vec::new(),
             curr_flight_mode: FlightMode::Manual,
-            controls: Controls {
-                throttle: 0,
-                elevation: 0,
-                yaw: 0,
-            },
+            armed: false,
+            controls: Controls::default(),
             altitude: None,
-            gps_location: None,
+            vertical_speed: None,
+            estimator: AltitudeEstimator::new(EstimatorConfig::default()),
+            last_estimate: None,
+            pressure: None,
+            last_baro: None,
+            base_pressure: STANDARD_PRESSURE,
+            temperature: STANDARD_TEMPERATURE,
+            heading: None,
+            attitude: None,
+            attitude_estimator: AttitudeEstimator::new(AttitudeConfig::default()),
+            last_gyro: None,
+            last_accel: None,
+            last_mag: None,
+            gps_fix: None,
+            launch_location: None,
+            failsafe: Failsafe::new(FailsafeConfig::default()),
+            last_step: None,
+            last_reference_report: None,
+            last_attitude_report: None,
+            last_altitude_report: None,
+            clock: Arc::new(SystemClock::new()),
+            now: Duration::ZERO,
+            hold: HoldState::new(),
+            mixer: Mixer::quad_vectored(),
+            actuators: Actuators::default(),
+            saturated: Vec::new(),
+            decoder: FrameDecoder::new(),
+            session,
+            uplink_session: None,
+            uplink_window: SequenceWindow::new(),
+            newest_command: None,
+            link_monitor: LinkMonitor::new(LinkMonitorConfig::default()),
+            peer: None,
+            verifier: MessageVerifier::new(auth_key, session),
+            frame_errors: 0,
+            reported_frame_errors: 0,
+            auth_rejected: 0,
+            reported_auth_rejected: 0,
+        }
+    }
+
+    // Time source for everything time related - filters, timeouts, controllers
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn flight_mode(&self) -> FlightMode {
+        self.curr_flight_mode
+    }
+
+    // Switches flight mode if it is safe to do so, otherwise returns the reason why not
+    pub fn set_flight_mode(&mut self, mode: FlightMode) -> Result<(), String> {
+        if mode == self.curr_flight_mode {
+            return Ok(());
+        }
+        match mode {
+            FlightMode::Manual => {}
+            FlightMode::StabilizeAttiAlti => {
+                if !self.altitude_healthy() {
+                    return Err("No valid altitude - barometer data missing or stale".into());
+                }
+            }
         }
+        // Hold targets are captured anew from the current state
+        self.hold.reset();
+        self.curr_flight_mode = mode;
+        Ok(())
     }
 
-    pub async fn step(&mut self) {
+    // Sets reference (zero altitude) pressure and temperature, e.g. QNH from local weather
+    pub fn set_baro_reference(&mut self, pressure: f64, temperature: f64) -> Result<(), String> {
+        if !(pressure.is_finite() && pressure > 0.0) {
+            return Err(format!("Invalid reference pressure {pressure} Pa"));
+        }
+        if !(temperature.is_finite() && temperature > 0.0) {
+            return Err(format!("Invalid reference temperature {temperature} K"));
+        }
+        let prev_altitude = self.pressure.map(|press| self.pressure_altitude(press));
+        self.base_pressure = pressure;
+        self.temperature = temperature;
+
+        if let (Some(press), Some(prev)) = (self.pressure, prev_altitude) {
+            let delta = self.pressure_altitude(press) - prev;
+            self.estimator.offset(delta);
+            self.altitude = self.estimator.altitude();
+            // Keep holding the same physical altitude despite the reference change
+            if let Some(target) = self.hold.target_altitude.as_mut() {
+                *target += delta;
+            }
+        }
+        Ok(())
+    }
+
+    // Makes current altitude the zero altitude
+    pub fn calibrate_altitude_zero(&mut self) -> Result<(), String> {
+        let Some(press) = self.pressure else {
+            return Err("No barometer data received yet".into());
+        };
+        if self.armed {
+            return Err("Zero altitude calibration is only allowed while disarmed".into());
+        }
+        self.set_baro_reference(press, self.temperature)
+    }
+
+    pub fn altitude(&self) -> Option<f64> {
+        self.altitude
+    }
+
+    // Whether altitude estimate is backed by a recent barometer sample
+    fn altitude_healthy(&self) -> bool {
+        self.altitude.is_some()
+            && self
+                .last_baro
+                .is_some_and(|last| self.seconds_since(last) <= BARO_SAMPLE_TIMEOUT)
+    }
+
+    pub fn vertical_speed(&self) -> Option<f64> {
+        self.vertical_speed
+    }
+
+    // Latitude and longitude of last fix, if it is usable and recent
+    pub fn gps_location(&self) -> Option<(f64, f64)> {
+        self.valid_gps_fix()
+            .map(|fix| (fix.latitude, fix.longitude))
+    }
+
+    pub fn valid_gps_fix(&self) -> Option<&GpsFix> {
+        let (fix, received) = self.gps_fix.as_ref()?;
+        if self.seconds_since(*received) > GPS_FIX_TIMEOUT {
+            return None;
+        }
+        Some(fix)
+    }
+
+    fn handle_gps_fix(&mut self, fix: &GpsFix) {
+        let usable = fix.quality != GpsFixQuality::NoFix
+            && fix.satellites >= GPS_MIN_SATELLITES
+            && fix.hdop <= GPS_MAX_HDOP;
+        if !usable {
+            // Receiver lost the solution, don't keep using the old one until timeout
+            self.gps_fix = None;
+            return;
+        }
+        if let Some((prev, _)) = self.gps_fix.as_ref() {
+            // Out of order or repeated solution
+            if fix.timestamp <= prev.timestamp {
+                return;
+            }
+        }
+        if fix.quality == GpsFixQuality::Fix3D {
+            self.predict_estimate();
+            self.estimator.update_gps(fix.altitude);
+            self.sync_estimate();
+        }
+        self.gps_fix = Some((fix.clone(), self.now));
+        if self.launch_location.is_none() {
+            self.launch_location = Some((fix.latitude, fix.longitude));
+        }
+    }
+
+    pub fn baro_reference(&self) -> (f64, f64) {
+        (self.base_pressure, self.temperature)
+    }
+
+    fn baro_reference_msg(&self) -> MessageB2G {
+        MessageB2G::BaroReference {
+            pressure: self.base_pressure,
+            temperature: self.temperature,
+        }
+    }
+
+    // Brings altitude estimate to the current time
+    fn predict_estimate(&mut self) {
+        let dt = time_step(&mut self.last_estimate, self.now);
+        let vertical_accel = self.fresh_imu_sample(self.last_accel).and_then(|accel| {
+            // Earth frame is north-east-down, specific force has gravity already subtracted
+            let accel = self.attitude_estimator.body_to_earth(accel)?;
+            Some(-(accel.z + GRAVITY))
+        });
+        self.estimator.predict(dt, vertical_accel);
+    }
+
+    fn fresh_imu_sample(&self, sample: Option<(Vector3, Duration)>) -> Option<Vector3> {
+        let (value, received) = sample?;
+        (self.seconds_since(received) <= IMU_SAMPLE_TIMEOUT).then_some(value)
+    }
+
+    fn handle_gyro(&mut self, gyro: Vector3) {
+        self.expire_attitude();
+        let dt = time_step(&mut self.last_gyro, self.now);
+        let accel = self.fresh_imu_sample(self.last_accel);
+        let mag = self.fresh_imu_sample(self.last_mag);
+        self.attitude_estimator.update(gyro, accel, mag, dt);
+        self.attitude = self.attitude_estimator.attitude();
+    }
+
+    // Attitude can't be propagated without gyro, so after a gap it starts over
+    fn expire_attitude(&mut self) {
+        if self
+            .last_gyro
+            .is_some_and(|last| self.seconds_since(last) > IMU_SAMPLE_TIMEOUT)
+        {
+            self.attitude_estimator.reset();
+            self.attitude = None;
+            self.last_gyro = None;
+        }
+    }
+
+    pub fn attitude(&self) -> Option<Attitude> {
+        self.attitude
+    }
+
+    // Heading from attitude estimate while magnetometer keeps its yaw referenced to north,
+    // otherwise from compass
+    pub fn heading(&self) -> Option<f64> {
+        let referenced = self.fresh_imu_sample(self.last_mag).is_some();
+        let yaw = self.attitude.filter(|_| referenced).map(|att| att.yaw);
+        yaw.or(self.heading)
+    }
+
+    // Takes over the estimator's output for control and telemetry
+    fn sync_estimate(&mut self) {
+        self.altitude = self.estimator.altitude();
+        self.vertical_speed = self.estimator.vertical_speed();
+    }
+
+    fn pressure_altitude(&self, press: f64) -> f64 {
+        // See: https://en.wikipedia.org/wiki/Barometric_formula
+        // p = p_b * exp(-g * M * h / R / T)
+        // ln (p / p_b) = -g * M * h / R / T
+        // h = (ln p - ln p_b) * (-R) * T / g / M
+        // h = (ln p_b - ln p) * R * T / g / M
+        let const_coef: f64 = 0.0292718; // R / g / M
+        (self.base_pressure.ln() - press.ln()) * const_coef * self.temperature
+    }
+
+    // One control loop iteration - failsafe checks, then the current flight mode
+    fn control(&mut self) {
+        if self.frame_errors != self.reported_frame_errors {
+            self.reported_frame_errors = self.frame_errors;
+            self.send_msg(&MessageB2G::FrameErrors(self.frame_errors));
+        }
+        if report_due(
+            &mut self.last_reference_report,
+            self.now,
+            BARO_REFERENCE_REPORT_PERIOD,
+        ) {
+            self.send_msg(&self.baro_reference_msg());
+        }
+        self.expire_attitude();
+        if let Some(attitude) = self.attitude {
+            if report_due(
+                &mut self.last_attitude_report,
+                self.now,
+                ATTITUDE_REPORT_PERIOD,
+            ) {
+                self.send_msg(&MessageB2G::Attitude(attitude));
+            }
+        }
+        if let (Some(altitude), Some(vertical_speed), Some(variance)) = (
+            self.altitude,
+            self.vertical_speed,
+            self.estimator.altitude_variance(),
+        ) {
+            if report_due(
+                &mut self.last_altitude_report,
+                self.now,
+                ALTITUDE_REPORT_PERIOD,
+            ) {
+                self.send_msg(&MessageB2G::AltitudeEstimate {
+                    altitude,
+                    vertical_speed,
+                    altitude_std: variance.sqrt(),
+                });
+            }
+        }
+        if self.auth_rejected != self.reported_auth_rejected {
+            self.reported_auth_rejected = self.auth_rejected;
+            self.send_msg(&MessageB2G::AuthRejected(self.auth_rejected));
+        }
+        // Link quality is measured only once there's a ground station to answer
+        if self.peer.is_some() {
+            if let Some(id) = self.link_monitor.poll(self.now) {
+                self.send_msg(&MessageB2G::Ping(id));
+                self.send_msg(&MessageB2G::LinkStats(self.link_stats()));
+            }
+        }
+        self.failsafe.set_packet_loss(self.link_stats().packet_loss);
+        let dt = time_step(&mut self.last_step, self.now);
+
+        self.update_failsafe();
+        if let FailsafeState::Triggered(behavior) = self.failsafe.state() {
+            self.run_failsafe(behavior, dt);
+            return;
+        }
+
         match self.curr_flight_mode {
             FlightMode::Manual => {
-                self.action_callback.as_ref().map(|x| {
-                    for i in 0..4 {
-                        let speed: i32 = self.controls.throttle
-                            + (if i % 2 == 0 { 1 } else { -1 }) * self.controls.yaw
-                            + self.controls.elevation;
-                        //Motor
-                        self.perform_action(x, BlimpAction::SetMotor { motor: i, speed });
-                        // Up-down servo
-                        self.perform_action(
-                            x,
-                            BlimpAction::SetServo {
-                                servo: 2 * i,
-                                location: self.controls.elevation as i16,
-                            },
-                        );
-                        //Sideways servo
-                        self.perform_action(
-                            x,
-                            BlimpAction::SetServo {
-                                servo: 2 * i + 1,
-                                location: self.controls.yaw as i16,
-                            },
-                        );
+                self.apply_outputs(
+                    self.controls.throttle,
+                    self.controls.elevation,
+                    self.controls.yaw,
+                    dt,
+                );
+            }
+            FlightMode::StabilizeAttiAlti => {
+                let elevation = self.altitude_hold(dt, self.controls.elevation);
+                let yaw = self.heading_hold(dt, self.controls.yaw);
+                // Throttle is not held, so it still commands forward thrust directly
+                self.apply_outputs(self.controls.throttle, elevation, yaw, dt);
+            }
+        }
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    // Enables motors if pre-arm checks pass, otherwise returns the reason why not
+    pub fn arm(&mut self) -> Result<(), String> {
+        if self.armed {
+            return Ok(());
+        }
+        if self.controls.throttle != 0 {
+            return Err("Throttle is not at zero".into());
+        }
+        if !self.altitude_healthy() {
+            return Err("No valid altitude - barometer data missing or stale".into());
+        }
+        if !self.failsafe.link_established() || self.failsafe.state() != FailsafeState::Nominal {
+            return Err("Ground link is not alive".into());
+        }
+        if self.gps_location().is_some() {
+            self.launch_location = self.gps_location();
+        }
+        self.hold.reset();
+        self.armed = true;
+        Ok(())
+    }
+
+    // Always possible, also in flight
+    pub fn disarm(&mut self) {
+        self.armed = false;
+    }
+
+    // Uplink messages dropped for failing authentication
+    pub fn auth_rejected(&self) -> u64 {
+        self.auth_rejected
+    }
+
+    pub fn link_stats(&self) -> LinkStats {
+        self.link_monitor.stats(self.now)
+    }
+
+    // Ground station's Hello, if it was compatible
+    pub fn peer(&self) -> Option<&Hello> {
+        self.peer.as_ref()
+    }
+
+    // Uplink frames dropped for failing CRC or not decoding to a message
+    pub fn frame_errors(&self) -> u64 {
+        self.frame_errors
+    }
+
+    pub fn failsafe_state(&self) -> FailsafeState {
+        self.failsafe.state()
+    }
+
+    fn update_failsafe(&mut self) {
+        let hover_possible = self.altitude_healthy();
+        let return_possible = hover_possible
+            && self.heading().is_some()
+            && self.launch_location.is_some()
+            && self.gps_location().is_some();
+        let transition = self.failsafe.update(self.now, |behavior| match behavior {
+            FailsafeBehavior::MotorsOff => true,
+            FailsafeBehavior::HoverHold => hover_possible,
+            FailsafeBehavior::ReturnToLaunch => return_possible,
+        });
+        if let Some(state) = transition {
+            // Last stick command is stale, only fresh ones may apply once link is back
+            if matches!(state, FailsafeState::Triggered(..)) {
+                self.controls = Controls::default();
+            }
+            self.hold.reset();
+            self.send_msg(&MessageB2G::FailsafeStatus(state));
+        }
+    }
+
+    fn run_failsafe(&mut self, behavior: FailsafeBehavior, dt: f64) {
+        match behavior {
+            FailsafeBehavior::MotorsOff => {
+                self.apply_outputs(0, 0, 0, dt);
+            }
+            FailsafeBehavior::HoverHold => {
+                let elevation = self.altitude_hold(dt, 0);
+                let yaw = self.heading_hold(dt, 0);
+                self.apply_outputs(0, elevation, yaw, dt);
+            }
+            FailsafeBehavior::ReturnToLaunch => {
+                let mut throttle = 0;
+                if let (Some(here), Some(launch)) = (self.gps_location(), self.launch_location) {
+                    let (distance, bearing) = distance_bearing(here, launch);
+                    if distance > RTL_ARRIVAL_RADIUS {
+                        self.hold.target_heading = Some(bearing);
+                        throttle = (distance * RTL_THROTTLE_GAIN).min(RTL_MAX_THROTTLE) as i32;
                     }
-                });
+                }
+                let elevation = self.altitude_hold(dt, 0);
+                let yaw = self.heading_hold(dt, 0);
+                self.apply_outputs(throttle, elevation, yaw, dt);
             }
-            FlightMode::StabilizeAttiAlti => {}
         }
     }
 
-    fn perform_action(
-        &self,
-        action_callback: &(dyn Fn(BlimpAction) -> () + Send),
-        action: BlimpAction,
-    ) {
-        action_callback(action.clone());
+    // Returns elevation command holding target altitude, which elevation stick nudges
+    fn altitude_hold(&mut self, dt: f64, stick: i32) -> i32 {
+        let Some(altitude) = self.altitude.filter(|_| self.altitude_healthy()) else {
+            // No feedback, or just extrapolated since barometer died - stay neutral and start
+            // from scratch once it arrives
+            self.hold.target_altitude = None;
+            self.hold.altitude_pid.reset();
+            return 0;
+        };
+        let target = self.hold.target_altitude.get_or_insert(altitude);
+        *target += stick as f64 * ALTITUDE_NUDGE_RATE * dt;
+        let vertical_speed = self.vertical_speed.unwrap_or(0.0);
+        self.hold
+            .altitude_pid
+            .update_with_rate(*target, altitude, vertical_speed, dt) as i32
+    }
+
+    // Returns yaw command holding target heading, which yaw stick nudges
+    fn heading_hold(&mut self, dt: f64, stick: i32) -> i32 {
+        let Some(heading) = self.heading() else {
+            self.hold.target_heading = None;
+            self.hold.heading_pid.reset();
+            return 0;
+        };
+        let target = self.hold.target_heading.get_or_insert(heading);
+        *target = wrap_angle(*target + stick as f64 * HEADING_NUDGE_RATE * dt);
+        self.hold.heading_pid.update(*target, heading, dt) as i32
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    // Configures airframe's actuator layout
+    pub fn set_mixer(&mut self, mixer: Mixer) {
+        self.mixer = mixer;
+    }
+
+    pub fn set_actuator_config(
+        &mut self,
+        kind: OutputKind,
+        channel: u8,
+        config: ActuatorConfig,
+    ) -> Result<(), String> {
+        self.actuators.set_config(kind, channel, config)
+    }
+
+    fn apply_outputs(&mut self, throttle: i32, elevation: i32, yaw: i32, dt: f64) {
+        let mut actions = Vec::new();
+        let mut saturated = Vec::new();
+        for (kind, channel, command) in
+            self.mixer
+                .mix(throttle as f64, elevation as f64, yaw as f64)
+        {
+            let (value, saturation) = match kind {
+                OutputKind::Motor if !self.armed => {
+                    // Cut immediately, and ramp up from zero once armed again
+                    self.actuators.force(kind, channel, 0.0);
+                    (0.0, false)
+                }
+                _ => self.actuators.apply(kind, channel, command, dt),
+            };
+            if saturation {
+                saturated.push((kind, channel));
+            }
+            actions.push(match kind {
+                OutputKind::Motor => BlimpAction::SetMotor {
+                    motor: channel,
+                    speed: value as i32,
+                },
+                OutputKind::Servo => BlimpAction::SetServo {
+                    servo: channel,
+                    location: value as i16,
+                },
+            });
+        }
+
+        if saturated != self.saturated {
+            self.saturated = saturated;
+            self.send_msg(&MessageB2G::ActuatorSaturation(self.saturated.clone()));
+        }
+        for action in actions {
+            self.perform_action(action);
+        }
+    }
+
+    // Lets a restarted ground station sign without its messages looking like replays
+    fn hello(&self) -> Hello {
+        Hello {
+            next_counter: self.verifier.next_counter(),
+            ..Hello::current(self.session)
+        }
+    }
+
+    // Ground station can't sign anything before it learns the session from our Hello, so an
+    // unsigned Hello is answered with it. Nothing else is, and nothing changes state.
+    fn handle_unauthenticated(&mut self, payload: &[u8]) {
+        match postcard::from_bytes::<Packet<MessageG2B>>(payload) {
+            Ok(Packet {
+                msg: MessageG2B::Hello(_),
+                ..
+            }) => {
+                self.send_msg(&MessageB2G::Hello(self.hello()));
+            }
+            // Not even proof that the link works
+            _ => self.auth_rejected += 1,
+        }
+    }
+
+    // Acknowledges reliable packets and drops duplicate and stale ones
+    fn handle_packet(&mut self, packet: Packet<MessageG2B>) {
+        // Ground station restarted, and with it the numbering. Retransmitted or reordered
+        // Hellos of the current session are just deduplicated like any other packet.
+        if let MessageG2B::Hello(hello) = &packet.msg {
+            if self.uplink_session != Some(hello.session) {
+                self.uplink_session = Some(hello.session);
+                self.uplink_window.reset();
+                self.newest_command = None;
+            }
+        }
+        let acceptance = self.uplink_window.check(packet.seq);
+        if packet.reliable {
+            // Also when duplicate, the previous acknowledgement may have been lost
+            self.send_msg(&MessageB2G::Ack(packet.seq));
+        }
+        // Retransmitted commands may arrive after newer stick packets, which are latest-wins,
+        // but mustn't undo a newer command, e.g. re-arm after a disarm. Disarm is always safe.
+        let superseded = self.newest_command.is_some_and(|seq| packet.seq < seq)
+            && !matches!(packet.msg, MessageG2B::Disarm);
+        let apply = match acceptance {
+            Acceptance::New => true,
+            Acceptance::Late => packet.reliable && !superseded,
+            Acceptance::Duplicate | Acceptance::TooOld => false,
+        };
+        if apply {
+            self.uplink_window.record(packet.seq);
+            if packet.reliable {
+                self.newest_command = Some(packet.seq);
+            }
+            self.handle_message(packet.msg);
+        }
+    }
+
+    fn handle_message(&mut self, msg: MessageG2B) {
+        match msg {
+            MessageG2B::Ping(id) => {
+                self.send_msg(&MessageB2G::Pong(id));
+            }
+            MessageG2B::Pong(id) => {
+                self.link_monitor.pong(id, self.now);
+            }
+            MessageG2B::Hello(hello) => {
+                self.send_msg(&MessageB2G::Hello(self.hello()));
+                match hello.compatible() {
+                    Ok(()) => {
+                        // New session, statistics of the previous one don't apply
+                        self.link_monitor.reset();
+                        self.peer = Some(hello);
+                    }
+                    Err(reason) => {
+                        // Nothing the previous peer commanded may keep flying the blimp
+                        self.peer = None;
+                        self.controls = Controls::default();
+                        self.hold.reset();
+                        self.send_msg(&MessageB2G::HelloRejected(reason));
+                    }
+                }
+            }
+            // Stopping motors is allowed to anyone, whatever the handshake state
+            MessageG2B::Disarm => {
+                self.disarm();
+                self.send_msg(&MessageB2G::ArmingState(false));
+            }
+            // Stick updates are too frequent to answer each
+            MessageG2B::Control(_) if self.peer.is_none() => {}
+            _ if self.peer.is_none() => {
+                self.send_msg(&MessageB2G::CommandRefused(
+                    "No compatible Hello received".into(),
+                ));
+            }
+            MessageG2B::Control(ctrl) => {
+                self.controls = ctrl;
+            }
+            MessageG2B::SetFlightMode(mode) => {
+                let reply = match self.set_flight_mode(mode) {
+                    Ok(()) => MessageB2G::FlightModeAck(mode),
+                    Err(reason) => MessageB2G::FlightModeRejected(mode, reason),
+                };
+                self.send_msg(&reply);
+            }
+            MessageG2B::SetBaroReference {
+                pressure,
+                temperature,
+            } => {
+                let reply = match self.set_baro_reference(pressure, temperature) {
+                    Ok(()) => self.baro_reference_msg(),
+                    Err(reason) => MessageB2G::BaroReferenceRejected(reason),
+                };
+                self.send_msg(&reply);
+            }
+            MessageG2B::SetFailsafeConfig(config) => {
+                let reply = match self.failsafe.set_config(config.clone()) {
+                    Ok(()) => MessageB2G::FailsafeConfigAck(config),
+                    Err(reason) => MessageB2G::FailsafeConfigRejected(reason),
+                };
+                self.send_msg(&reply);
+            }
+            MessageG2B::Arm => {
+                let reply = match self.arm() {
+                    Ok(()) => MessageB2G::ArmingState(true),
+                    Err(reason) => MessageB2G::ArmRejected(reason),
+                };
+                self.send_msg(&reply);
+            }
+            MessageG2B::SetActuatorConfig(kind, channel, config) => {
+                let reply = match self.actuators.set_config(kind, channel, config) {
+                    Ok(()) => MessageB2G::ActuatorConfigAck(kind, channel),
+                    Err(reason) => MessageB2G::ActuatorConfigRejected(kind, channel, reason),
+                };
+                self.send_msg(&reply);
+            }
+            MessageG2B::CalibrateAltitudeZero => {
+                let reply = match self.calibrate_altitude_zero() {
+                    Ok(()) => self.baro_reference_msg(),
+                    Err(reason) => MessageB2G::BaroReferenceRejected(reason),
+                };
+                self.send_msg(&reply);
+            }
+        }
+    }
+
+    fn seconds_since(&self, time: Duration) -> f64 {
+        self.now.saturating_sub(time).as_secs_f64()
+    }
+
+    fn send_msg(&mut self, msg: &MessageB2G) {
+        let payload = postcard::to_stdvec::<MessageB2G>(msg).unwrap();
+        self.pending
+            .push(BlimpAction::SendMsg(encode_frame(&payload)));
+    }
+
+    fn perform_action(&mut self, action: BlimpAction) {
+        self.pending.push(action.clone());
         if matches!(
             action,
             BlimpAction::SetMotor { .. } | BlimpAction::SetServo { .. }
         ) {
-            action_callback(BlimpAction::SendMsg(
-                postcard::to_stdvec::<MessageB2G>(&MessageB2G::ForwardAction(action)).unwrap(),
+            self.send_msg(&MessageB2G::ForwardAction(action));
+        }
+    }
+
+    fn flush(&mut self, out: &mut dyn ActionSink<BlimpAction>) {
+        for action in self.pending.drain(..) {
+            out.emit(action);
+        }
+    }
+}
+
+// Returns seconds elapsed since `last` and moves it to `now`
+fn time_step(last: &mut Option<Duration>, now: Duration) -> f64 {
+    let dt = last.map_or(0.0, |last| now.saturating_sub(last).as_secs_f64());
+    *last = Some(now);
+    dt
+}
+
+// Returns whether at least `period` seconds passed since `last`, moving it to `now` if so
+fn report_due(last: &mut Option<Duration>, now: Duration, period: f64) -> bool {
+    if last.is_some_and(|last| now.saturating_sub(last).as_secs_f64() < period) {
+        return false;
+    }
+    *last = Some(now);
+    true
+}
+
+// Wraps angle in radians into -PI..PI
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + std::f64::consts::PI).rem_euclid(std::f64::consts::TAU) - std::f64::consts::PI
+}
+
+// Distance (m) and bearing (rad, clockwise from north) between two (lat, lon) points in
+// degrees, flat earth approximation good enough for short distances
+fn distance_bearing(from: (f64, f64), to: (f64, f64)) -> (f64, f64) {
+    let north = (to.0 - from.0).to_radians() * EARTH_RADIUS;
+    let east = (to.1 - from.1).to_radians() * EARTH_RADIUS * from.0.to_radians().cos();
+    (north.hypot(east), east.atan2(north))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obsw_auth::MessageSigner;
+    use crate::obsw_framing::decode_frame;
+    use crate::obsw_link::{ReliableSender, RetransmitConfig};
+    use crate::obsw_runtime::block_on;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn setup() -> (BlimpMainAlgo, Arc<MockClock>) {
+        let mut algo = BlimpMainAlgo::new(KEY);
+        let clock = Arc::new(MockClock::default());
+        algo.set_clock(clock.clone());
+        send(
+            &mut algo,
+            &mut Vec::new(),
+            MessageG2B::Hello(Hello::current(GROUND_SESSION)),
+        );
+        (algo, clock)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    const KEY: &[u8] = b"test key";
+    const GROUND_SESSION: u64 = 1;
+
+    // Shared by all tests, so still increasing for each algorithm instance
+    static NEXT_SEQ: AtomicU32 = AtomicU32::new(0);
+
+    fn send(algo: &mut BlimpMainAlgo, out: &mut Vec<BlimpAction>, msg: MessageG2B) {
+        let packet = Packet {
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+            reliable: msg.reliable(),
+            msg,
+        };
+        send_packet(algo, out, &packet);
+    }
+
+    fn send_packet(
+        algo: &mut BlimpMainAlgo,
+        out: &mut Vec<BlimpAction>,
+        packet: &Packet<MessageG2B>,
+    ) {
+        let frame = signed_frame(algo, packet);
+        block_on(algo.handle_event(&BlimpEvent::GetMsg(frame), out));
+    }
+
+    static NEXT_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    // Frame with the packet signed for the algorithm's session
+    fn signed_frame(algo: &BlimpMainAlgo, packet: &Packet<MessageG2B>) -> Vec<u8> {
+        let counter = NEXT_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut signer = MessageSigner::new(KEY, algo.session, counter);
+        encode_frame(&signer.seal(&postcard::to_stdvec(packet).unwrap()))
+    }
+
+    fn controls(throttle: i32, elevation: i32, yaw: i32) -> MessageG2B {
+        MessageG2B::Control(Controls {
+            throttle,
+            elevation,
+            yaw,
+        })
+    }
+
+    fn motor_speeds(actions: &mut Vec<BlimpAction>) -> Vec<i32> {
+        actions
+            .drain(..)
+            .filter_map(|action| match action {
+                BlimpAction::SetMotor { speed, .. } => Some(speed),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Messages sent to ground, except acknowledgements
+    fn replies(actions: &mut Vec<BlimpAction>) -> Vec<MessageB2G> {
+        actions
+            .drain(..)
+            .filter_map(|action| match action {
+                BlimpAction::SendMsg(bytes) => {
+                    postcard::from_bytes(&decode_frame(&bytes).unwrap()).ok()
+                }
+                _ => None,
+            })
+            .filter(|msg| !matches!(msg, MessageB2G::Ack(_)))
+            .collect()
+    }
+
+    fn feed_baro(algo: &mut BlimpMainAlgo, out: &mut Vec<BlimpAction>) {
+        let ev = BlimpEvent::SensorDataF64(SensorType::Barometer, 100000.0);
+        block_on(algo.handle_event(&ev, out));
+    }
+
+    fn gps_fix(timestamp: u64) -> GpsFix {
+        GpsFix {
+            latitude: 50.0,
+            longitude: 20.0,
+            altitude: 300.0,
+            quality: GpsFixQuality::Fix3D,
+            satellites: 9,
+            hdop: 1.0,
+            timestamp,
+        }
+    }
+
+    fn servo_locations(actions: &mut Vec<BlimpAction>) -> Vec<(u8, i16)> {
+        actions
+            .drain(..)
+            .filter_map(|action| match action {
+                BlimpAction::SetServo { servo, location } => Some((servo, location)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flight_mode_change_needs_altitude() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        let stabilize = MessageG2B::SetFlightMode(FlightMode::StabilizeAttiAlti);
+        send(&mut algo, &mut actions, stabilize.clone());
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::FlightModeRejected(
+                FlightMode::StabilizeAttiAlti,
+                _
+            )]
+        ));
+        assert_eq!(algo.flight_mode(), FlightMode::Manual);
+
+        feed_baro(&mut algo, &mut actions);
+        actions.clear();
+        send(&mut algo, &mut actions, stabilize);
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::FlightModeAck(FlightMode::StabilizeAttiAlti)]
+        ));
+        assert_eq!(algo.flight_mode(), FlightMode::StabilizeAttiAlti);
+    }
+
+    #[test]
+    fn reference_change_keeps_physical_hold_target() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        algo.set_flight_mode(FlightMode::StabilizeAttiAlti).unwrap();
+        block_on(algo.step(&mut actions));
+        algo.hold.target_altitude = Some(algo.altitude().unwrap() + 1.0);
+
+        algo.set_baro_reference(102000.0, 280.0).unwrap();
+        let above = algo.hold.target_altitude.unwrap() - algo.altitude().unwrap();
+        assert!((above - 1.0).abs() < 1e-9, "{above}");
+
+        // And the new reference shows up in telemetry
+        clock.advance(ms(1000));
+        block_on(algo.step(&mut actions));
+        assert!(replies(&mut actions).iter().any(|msg| matches!(
+            msg,
+            MessageB2G::BaroReference {
+                pressure: 102000.0,
+                temperature: 280.0
+            }
+        )));
+    }
+
+    #[test]
+    fn invalid_baro_reference_is_rejected() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        actions.clear();
+        let altitude = algo.altitude();
+        for (pressure, temperature) in [
+            (0.0, STANDARD_TEMPERATURE),
+            (f64::NAN, STANDARD_TEMPERATURE),
+            (STANDARD_PRESSURE, -10.0),
+            (STANDARD_PRESSURE, f64::INFINITY),
+        ] {
+            let msg = MessageG2B::SetBaroReference {
+                pressure,
+                temperature,
+            };
+            send(&mut algo, &mut actions, msg);
+            assert!(matches!(
+                replies(&mut actions)[..],
+                [MessageB2G::BaroReferenceRejected(_)]
             ));
         }
+        assert_eq!(
+            algo.baro_reference(),
+            (STANDARD_PRESSURE, STANDARD_TEMPERATURE)
+        );
+        assert_eq!(algo.altitude(), altitude);
+    }
+
+    #[test]
+    fn zero_calibration_is_refused_while_armed() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        actions.clear();
+        send(&mut algo, &mut actions, MessageG2B::CalibrateAltitudeZero);
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::BaroReferenceRejected(_)]
+        ));
+        assert_eq!(algo.baro_reference().0, STANDARD_PRESSURE);
+
+        send(&mut algo, &mut actions, MessageG2B::Disarm);
+        actions.clear();
+        send(&mut algo, &mut actions, MessageG2B::CalibrateAltitudeZero);
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::BaroReference {
+                pressure: 100000.0,
+                ..
+            }]
+        ));
+        assert!(algo.altitude().unwrap().abs() < 1e-9);
+    }
+
+    #[test]
+    fn invalid_altitude_measurements_are_ignored() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        let sample = |sensor, value| BlimpEvent::SensorDataF64(sensor, value);
+        for press in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            block_on(algo.handle_event(&sample(SensorType::Barometer, press), &mut actions));
+        }
+        assert_eq!(algo.altitude(), None);
+
+        feed_baro(&mut algo, &mut actions);
+        block_on(algo.handle_event(&sample(SensorType::Barometer, f64::NAN), &mut actions));
+        block_on(algo.handle_event(&sample(SensorType::GPSAltitude, f64::NAN), &mut actions));
+        let fix = GpsFix {
+            altitude: f64::INFINITY,
+            ..gps_fix(1)
+        };
+        block_on(algo.handle_event(&BlimpEvent::GpsFix(fix), &mut actions));
+        assert!(algo.altitude().unwrap().is_finite());
+        assert!(algo.vertical_speed().unwrap().is_finite());
+    }
+
+    #[test]
+    fn unusable_gps_fix_clears_position() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        let unusable = [
+            GpsFix {
+                quality: GpsFixQuality::NoFix,
+                ..gps_fix(2)
+            },
+            GpsFix {
+                satellites: GPS_MIN_SATELLITES - 1,
+                ..gps_fix(2)
+            },
+            GpsFix {
+                hdop: GPS_MAX_HDOP + 1.0,
+                ..gps_fix(2)
+            },
+        ];
+        for fix in unusable {
+            block_on(algo.handle_event(&BlimpEvent::GpsFix(gps_fix(1)), &mut actions));
+            assert_eq!(algo.gps_location(), Some((50.0, 20.0)));
+            block_on(algo.handle_event(&BlimpEvent::GpsFix(fix), &mut actions));
+            assert_eq!(algo.gps_location(), None);
+        }
+    }
+
+    #[test]
+    fn gps_fix_out_of_order_is_ignored_and_goes_stale() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        block_on(algo.handle_event(&BlimpEvent::GpsFix(gps_fix(2000)), &mut actions));
+        let older = GpsFix {
+            latitude: 51.0,
+            ..gps_fix(1000)
+        };
+        block_on(algo.handle_event(&BlimpEvent::GpsFix(older), &mut actions));
+        assert_eq!(algo.gps_location(), Some((50.0, 20.0)));
+
+        clock.advance(Duration::from_secs_f64(GPS_FIX_TIMEOUT));
+        block_on(algo.step(&mut actions));
+        assert_eq!(algo.gps_location(), Some((50.0, 20.0)));
+        clock.advance(ms(100));
+        block_on(algo.step(&mut actions));
+        assert_eq!(algo.gps_location(), None);
+    }
+
+    #[test]
+    fn only_3d_fix_feeds_altitude() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        let altitude = algo.altitude();
+        // Had the 2D fix set the GPS datum, the 3D one would pull the estimate down
+        let fix_2d = GpsFix {
+            quality: GpsFixQuality::Fix2D,
+            altitude: 1000.0,
+            ..gps_fix(1)
+        };
+        block_on(algo.handle_event(&BlimpEvent::GpsFix(fix_2d), &mut actions));
+        assert!(algo.gps_location().is_some());
+        block_on(algo.handle_event(&BlimpEvent::GpsFix(gps_fix(2)), &mut actions));
+        assert_eq!(algo.altitude(), altitude);
+
+        let higher = GpsFix {
+            altitude: 310.0,
+            ..gps_fix(3)
+        };
+        block_on(algo.handle_event(&BlimpEvent::GpsFix(higher), &mut actions));
+        assert!(algo.altitude().unwrap() > altitude.unwrap());
+    }
+
+    #[test]
+    fn altitude_estimate_is_reported_at_limited_rate() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        let mut reports = 0;
+        for _ in 0..100 {
+            // Barometer at 50 Hz
+            feed_baro(&mut algo, &mut actions);
+            block_on(algo.step(&mut actions));
+            reports += replies(&mut actions)
+                .iter()
+                .filter(|msg| matches!(msg, MessageB2G::AltitudeEstimate { .. }))
+                .count();
+            clock.advance(ms(20));
+        }
+        assert_eq!(reports, (2.0 / ALTITUDE_REPORT_PERIOD) as usize);
+    }
+
+    #[test]
+    fn attitude_is_reported_at_limited_rate() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        let gyro = BlimpEvent::SensorDataVec3(SensorType::Gyroscope, Vector3::default());
+        let accel = Vector3::new(0.0, 0.0, -GRAVITY);
+        let accel = BlimpEvent::SensorDataVec3(SensorType::Accelerometer, accel);
+        // 1 kHz IMU, 50 Hz control loop
+        for i in 0..1000 {
+            clock.advance(ms(1));
+            block_on(algo.handle_event(&accel, &mut actions));
+            block_on(algo.handle_event(&gyro, &mut actions));
+            if i % 20 == 19 {
+                block_on(algo.step(&mut actions));
+            }
+        }
+        assert!(algo.attitude().is_some());
+        let reports = replies(&mut actions)
+            .iter()
+            .filter(|msg| matches!(msg, MessageB2G::Attitude(_)))
+            .count();
+        assert!((9..=11).contains(&reports), "{reports}");
+    }
+
+    #[test]
+    fn heading_from_attitude_needs_magnetometer() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        let imu = |algo: &mut BlimpMainAlgo, actions: &mut Vec<BlimpAction>, mag: bool| {
+            let accel = Vector3::new(0.0, 0.0, -GRAVITY);
+            let mut events = vec![BlimpEvent::SensorDataVec3(SensorType::Accelerometer, accel)];
+            if mag {
+                // Field pointing north and down, so yaw is zero
+                let field = Vector3::new(0.2, 0.0, 0.4);
+                events.push(BlimpEvent::SensorDataVec3(SensorType::Magnetometer, field));
+            }
+            events.push(BlimpEvent::SensorDataVec3(
+                SensorType::Gyroscope,
+                Vector3::default(),
+            ));
+            for ev in &events {
+                block_on(algo.handle_event(ev, actions));
+            }
+        };
+        let compass = BlimpEvent::SensorDataF64(SensorType::Heading, 1.0);
+        block_on(algo.handle_event(&compass, &mut actions));
+        for _ in 0..10 {
+            clock.advance(ms(10));
+            imu(&mut algo, &mut actions, false);
+        }
+        assert!(algo.attitude().is_some());
+        assert_eq!(algo.heading(), Some(1.0));
+
+        for _ in 0..10 {
+            clock.advance(ms(10));
+            imu(&mut algo, &mut actions, true);
+        }
+        assert!(algo.heading().unwrap().abs() < 0.01);
+
+        // IMU goes silent, frozen attitude isn't used
+        clock.advance(Duration::from_secs_f64(IMU_SAMPLE_TIMEOUT) + ms(10));
+        block_on(algo.step(&mut actions));
+        assert!(algo.attitude().is_none());
+        assert_eq!(algo.heading(), Some(1.0));
+    }
+
+    #[test]
+    fn disarmed_never_drives_motors() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(800, 300, -400));
+        for mode in [FlightMode::Manual, FlightMode::StabilizeAttiAlti] {
+            algo.set_flight_mode(mode).unwrap();
+            for _ in 0..10 {
+                clock.advance(ms(20));
+                block_on(algo.step(&mut actions));
+            }
+            let speeds = motor_speeds(&mut actions);
+            assert!(!speeds.is_empty());
+            assert!(speeds.iter().all(|&speed| speed == 0), "{speeds:?}");
+        }
+    }
+
+    #[test]
+    fn arm_is_rejected_when_checks_fail() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::ArmRejected(_)]
+        ));
+
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(100, 0, 0));
+        actions.clear();
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::ArmRejected(_)]
+        ));
+        assert!(!algo.armed());
+    }
+
+    #[test]
+    fn stale_barometer_blocks_arming_and_altitude_hold() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        clock.advance(ms(1500));
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        assert!(algo.altitude().is_some());
+        assert!(algo.set_flight_mode(FlightMode::StabilizeAttiAlti).is_err());
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(!algo.armed());
+
+        feed_baro(&mut algo, &mut actions);
+        assert!(algo.set_flight_mode(FlightMode::StabilizeAttiAlti).is_ok());
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(algo.armed());
+    }
+
+    #[test]
+    fn motors_run_only_while_armed() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(algo.armed());
+
+        send(&mut algo, &mut actions, controls(500, 0, 0));
+        clock.advance(ms(20));
+        block_on(algo.step(&mut actions));
+        assert_eq!(motor_speeds(&mut actions), vec![500; 4]);
+
+        send(&mut algo, &mut actions, MessageG2B::Disarm);
+        clock.advance(ms(20));
+        block_on(algo.step(&mut actions));
+        assert_eq!(motor_speeds(&mut actions), vec![0; 4]);
+    }
+
+    #[test]
+    fn saturation_is_reported_for_outputs_sent() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        let config = ActuatorConfig {
+            max: 600.0,
+            ..ActuatorConfig::full_range(OutputKind::Motor)
+        };
+        algo.set_actuator_config(OutputKind::Motor, 0, config)
+            .unwrap();
+        feed_baro(&mut algo, &mut actions);
+        let saturation_reports = |algo: &mut BlimpMainAlgo, throttle| {
+            let mut actions = Vec::new();
+            send(algo, &mut actions, controls(throttle, 0, 0));
+            clock.advance(ms(20));
+            block_on(algo.step(&mut actions));
+            replies(&mut actions)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    MessageB2G::ActuatorSaturation(channels) => Some(channels),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Disarmed motors are off, whatever the command
+        assert!(saturation_reports(&mut algo, 800).is_empty());
+        saturation_reports(&mut algo, 0);
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert_eq!(
+            saturation_reports(&mut algo, 800),
+            vec![vec![(OutputKind::Motor, 0)]]
+        );
+        assert!(saturation_reports(&mut algo, 800).is_empty());
+        assert_eq!(saturation_reports(&mut algo, 500), vec![vec![]]);
+    }
+
+    #[test]
+    fn failsafe_triggers_on_clock_timeout() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        send(&mut algo, &mut actions, controls(700, 0, 0));
+
+        clock.advance(ms(2000));
+        feed_baro(&mut algo, &mut actions);
+        block_on(algo.step(&mut actions));
+        assert_eq!(algo.failsafe_state(), FailsafeState::Nominal);
+        assert_eq!(motor_speeds(&mut actions), vec![700; 4]);
+
+        clock.advance(ms(200));
+        block_on(algo.step(&mut actions));
+        assert_eq!(
+            algo.failsafe_state(),
+            FailsafeState::Triggered(FailsafeBehavior::HoverHold)
+        );
+        // Stale forward throttle is dropped
+        let speeds = motor_speeds(&mut actions);
+        assert!(speeds.iter().all(|&speed| speed.abs() < 700), "{speeds:?}");
+    }
+
+    #[test]
+    fn stale_barometer_makes_failsafe_fall_back_to_motors_off() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        algo.set_flight_mode(FlightMode::StabilizeAttiAlti).unwrap();
+        algo.hold.target_altitude = Some(algo.altitude().unwrap() + 10.0);
+        block_on(algo.step(&mut actions));
+        assert!(servo_locations(&mut actions).iter().any(|&(_, v)| v != 0));
+
+        // Altitude estimate still exists, but nothing backs it any more
+        clock.advance(Duration::from_secs_f64(BARO_SAMPLE_TIMEOUT) + ms(100));
+        block_on(algo.step(&mut actions));
+        assert!(algo.altitude().is_some());
+        assert_eq!(algo.failsafe_state(), FailsafeState::Nominal);
+        assert!(servo_locations(&mut actions).iter().all(|&(_, v)| v == 0));
+
+        clock.advance(ms(2000));
+        block_on(algo.step(&mut actions));
+        assert_eq!(
+            algo.failsafe_state(),
+            FailsafeState::Triggered(FailsafeBehavior::MotorsOff)
+        );
+    }
+
+    #[test]
+    fn runs_as_trait_object_on_another_thread() {
+        let mut algo: Box<dyn BlimpAlgorithm<BlimpEvent, BlimpAction>> = Box::new(setup().0);
+        let handle = std::thread::spawn(move || {
+            let mut actions = Vec::new();
+            let ev = BlimpEvent::SensorDataF64(SensorType::Barometer, 100000.0);
+            block_on(algo.handle_event(&ev, &mut actions));
+            block_on(algo.step(&mut actions));
+            actions
+        });
+        let actions = handle.join().unwrap();
+        assert!(actions
+            .iter()
+            .any(|action| matches!(action, BlimpAction::SetMotor { speed: 0, .. })));
+    }
+
+    #[test]
+    fn corrupt_uplink_is_counted_and_stream_resyncs() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        let ping = |id| Packet {
+            seq: 1000 + id,
+            reliable: false,
+            msg: MessageG2B::Ping(id),
+        };
+        let mut stream = signed_frame(&algo, &ping(1));
+        // Flipping a bit could turn a COBS code byte into a frame delimiter
+        stream.remove(1);
+        stream.extend(signed_frame(&algo, &ping(2)));
+        // Radio may split frames anywhere
+        let (first, second) = stream.split_at(5);
+        block_on(algo.handle_event(&BlimpEvent::GetMsg(first.to_vec()), &mut actions));
+        block_on(algo.handle_event(&BlimpEvent::GetMsg(second.to_vec()), &mut actions));
+        assert_eq!(algo.frame_errors(), 1);
+        assert!(matches!(replies(&mut actions)[..], [MessageB2G::Pong(2)]));
+
+        block_on(algo.step(&mut actions));
+        assert!(replies(&mut actions)
+            .iter()
+            .any(|msg| matches!(msg, MessageB2G::FrameErrors(1))));
+    }
+
+    #[test]
+    fn commands_need_compatible_hello() {
+        let mut algo = BlimpMainAlgo::new(KEY);
+        algo.set_clock(Arc::new(MockClock::default()));
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        actions.clear();
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        send(&mut algo, &mut actions, MessageG2B::Ping(7));
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::CommandRefused(_), MessageB2G::Pong(7)]
+        ));
+
+        let old = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::current(GROUND_SESSION)
+        };
+        send(&mut algo, &mut actions, MessageG2B::Hello(old));
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::Hello(_), MessageB2G::HelloRejected(_)]
+        ));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(!algo.armed());
+
+        send(
+            &mut algo,
+            &mut actions,
+            MessageG2B::Hello(Hello::current(GROUND_SESSION)),
+        );
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        assert!(algo.armed());
+    }
+
+    #[test]
+    fn incompatible_hello_in_flight_drops_control_but_not_disarm() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        send(&mut algo, &mut actions, controls(700, 0, 0));
+        clock.advance(ms(20));
+        block_on(algo.step(&mut actions));
+        assert_eq!(motor_speeds(&mut actions), vec![700; 4]);
+
+        let old = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..Hello::current(GROUND_SESSION)
+        };
+        send(&mut algo, &mut actions, MessageG2B::Hello(old));
+        clock.advance(ms(20));
+        block_on(algo.step(&mut actions));
+        assert_eq!(motor_speeds(&mut actions), vec![0; 4]);
+
+        // Traffic of an incompatible peer doesn't keep the link alive
+        for _ in 0..150 {
+            send(&mut algo, &mut actions, controls(700, 0, 0));
+            feed_baro(&mut algo, &mut actions);
+            clock.advance(ms(20));
+            block_on(algo.step(&mut actions));
+        }
+        assert!(matches!(algo.failsafe_state(), FailsafeState::Triggered(_)));
+        let speeds = motor_speeds(&mut actions);
+        assert!(speeds.iter().all(|&speed| speed.abs() < 700), "{speeds:?}");
+
+        send(&mut algo, &mut actions, MessageG2B::Disarm);
+        assert!(!algo.armed());
+    }
+
+    #[test]
+    fn duplicate_and_stale_packets_are_dropped() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        let packet = |seq, msg: MessageG2B| Packet {
+            seq,
+            reliable: msg.reliable(),
+            msg,
+        };
+        send_packet(&mut algo, &mut actions, &packet(5000, controls(300, 0, 0)));
+        // Older stick packet arriving late doesn't override the newer one
+        send_packet(&mut algo, &mut actions, &packet(4999, controls(100, 0, 0)));
+        assert_eq!(algo.controls.throttle, 300);
+
+        send_packet(&mut algo, &mut actions, &packet(5002, controls(0, 0, 0)));
+        // Reliable command arriving late after newer stick packets is still applied, but only
+        // once
+        let disarm = packet(5001, MessageG2B::Disarm);
+        for _ in 0..2 {
+            send_packet(&mut algo, &mut actions, &disarm);
+        }
+        let acks = actions
+            .iter()
+            .filter_map(|action| match action {
+                BlimpAction::SendMsg(bytes) => {
+                    postcard::from_bytes(&decode_frame(bytes).unwrap()).ok()
+                }
+                _ => None,
+            })
+            .filter(|msg| matches!(msg, MessageB2G::Ack(5001)))
+            .count();
+        assert_eq!(acks, 2);
+        assert!(matches!(
+            replies(&mut actions)[..],
+            [MessageB2G::ArmingState(false)]
+        ));
+    }
+
+    #[test]
+    fn late_command_doesnt_undo_newer_one() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        let packet = |seq, msg: MessageG2B| Packet {
+            seq,
+            reliable: msg.reliable(),
+            msg,
+        };
+        // Arm 9001 is lost, Disarm 9002 gets through, then Arm is retransmitted
+        send_packet(&mut algo, &mut actions, &packet(9002, MessageG2B::Disarm));
+        actions.clear();
+        send_packet(&mut algo, &mut actions, &packet(9001, MessageG2B::Arm));
+        assert!(!algo.armed());
+        // Acknowledged so it stops being retransmitted, but nothing else happens
+        let acked = actions.iter().any(|action| match action {
+            BlimpAction::SendMsg(bytes) => matches!(
+                postcard::from_bytes(&decode_frame(bytes).unwrap()),
+                Ok(MessageB2G::Ack(9001))
+            ),
+            _ => false,
+        });
+        assert!(acked);
+        assert!(replies(&mut actions).is_empty());
+    }
+
+    #[test]
+    fn late_hello_of_same_session_keeps_sequence_window() {
+        let (mut algo, _clock) = setup();
+        let mut actions = Vec::new();
+        let packet = |seq, msg: MessageG2B| Packet {
+            seq,
+            reliable: msg.reliable(),
+            msg,
+        };
+        let hello = |session| MessageG2B::Hello(Hello::current(session));
+        send_packet(&mut algo, &mut actions, &packet(11, hello(7)));
+        send_packet(&mut algo, &mut actions, &packet(20, controls(300, 0, 0)));
+        // Retransmitted Hello arriving late, then a stale stick packet
+        send_packet(&mut algo, &mut actions, &packet(10, hello(7)));
+        send_packet(&mut algo, &mut actions, &packet(18, controls(900, 0, 0)));
+        assert_eq!(algo.controls.throttle, 300);
+
+        // Restarted ground station numbers from zero in a new session
+        send_packet(&mut algo, &mut actions, &packet(0, hello(8)));
+        send_packet(&mut algo, &mut actions, &packet(1, controls(100, 0, 0)));
+        assert_eq!(algo.controls.throttle, 100);
+    }
+
+    #[test]
+    fn unauthenticated_commands_are_rejected_and_reported() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        let mut ground = ReliableSender::new(RetransmitConfig::default());
+        let mut deliver = |algo: &mut BlimpMainAlgo, ground: &mut ReliableSender<_>, msg| {
+            let frame = ground.send(msg, false, clock.now());
+            block_on(algo.handle_event(&BlimpEvent::GetMsg(frame), &mut actions));
+        };
+
+        deliver(&mut algo, &mut ground, controls(0, 0, 0));
+        ground.set_signer(Some(MessageSigner::new(b"guess", algo.session, 0)));
+        deliver(&mut algo, &mut ground, MessageG2B::Arm);
+        assert!(!algo.armed());
+        assert_eq!(algo.auth_rejected(), 2);
+
+        // Counters below the ones setup used would be replays
+        let counter = NEXT_COUNTER.load(Ordering::Relaxed);
+        ground.set_signer(Some(MessageSigner::new(KEY, algo.session, counter)));
+        // Sender numbers from zero again, so it's a new session
+        deliver(&mut algo, &mut ground, MessageG2B::Hello(Hello::current(2)));
+        deliver(&mut algo, &mut ground, controls(0, 0, 0));
+        deliver(&mut algo, &mut ground, MessageG2B::Arm);
+        assert!(algo.armed());
+
+        block_on(algo.step(&mut actions));
+        assert!(replies(&mut actions)
+            .iter()
+            .any(|msg| matches!(msg, MessageB2G::AuthRejected(2))));
+    }
+
+    #[test]
+    fn recording_from_before_reboot_is_rejected() {
+        let boot = || {
+            let mut algo = BlimpMainAlgo::new(KEY);
+            algo.set_clock(Arc::new(MockClock::default()));
+            feed_baro(&mut algo, &mut Vec::new());
+            algo
+        };
+        let mut algo = boot();
+        let mut actions = Vec::new();
+        let mut recording = Vec::new();
+        for (seq, msg) in [
+            MessageG2B::Hello(Hello::current(GROUND_SESSION)),
+            controls(0, 0, 0),
+            MessageG2B::Arm,
+            controls(1000, 0, 0),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let packet = Packet {
+                seq: seq as u32,
+                reliable: msg.reliable(),
+                msg,
+            };
+            recording.push(signed_frame(&algo, &packet));
+        }
+        for frame in &recording {
+            block_on(algo.handle_event(&BlimpEvent::GetMsg(frame.clone()), &mut actions));
+        }
+        assert!(algo.armed());
+
+        let mut rebooted = boot();
+        for frame in recording {
+            block_on(rebooted.handle_event(&BlimpEvent::GetMsg(frame), &mut actions));
+        }
+        assert!(!rebooted.armed());
+        assert!(rebooted.auth_rejected() > 0);
+
+        // Ground station learns the new session from the reply to its unsigned Hello
+        actions.clear();
+        let hello = Packet {
+            seq: 0,
+            reliable: true,
+            msg: MessageG2B::Hello(Hello::current(GROUND_SESSION)),
+        };
+        let frame = encode_frame(&postcard::to_stdvec(&hello).unwrap());
+        block_on(rebooted.handle_event(&BlimpEvent::GetMsg(frame), &mut actions));
+        assert!(matches!(
+            &replies(&mut actions)[..],
+            [MessageB2G::Hello(Hello { session, .. })] if *session == rebooted.session
+        ));
+        assert!(rebooted.peer().is_none());
+    }
+
+    #[test]
+    fn restarted_ground_station_can_disarm() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        send(&mut algo, &mut actions, controls(0, 0, 0));
+        send(&mut algo, &mut actions, MessageG2B::Arm);
+        for _ in 0..200 {
+            send(&mut algo, &mut actions, controls(300, 0, 0));
+        }
+        assert!(algo.armed());
+
+        // Ground station knows neither session nor counter after restart, so asks unsigned
+        let mut ground = ReliableSender::new(RetransmitConfig::default());
+        let hello = MessageG2B::Hello(Hello::current(GROUND_SESSION + 1));
+        actions.clear();
+        let frame = ground.send(hello.clone(), true, clock.now());
+        block_on(algo.handle_event(&BlimpEvent::GetMsg(frame), &mut actions));
+        let [MessageB2G::Hello(reply)] = &replies(&mut actions)[..] else {
+            panic!("No Hello reply");
+        };
+        ground.set_signer(Some(MessageSigner::new(
+            KEY,
+            reply.session,
+            reply.next_counter,
+        )));
+        for msg in [hello, MessageG2B::Disarm] {
+            let frame = ground.send(msg, true, clock.now());
+            block_on(algo.handle_event(&BlimpEvent::GetMsg(frame), &mut actions));
+        }
+        assert!(!algo.armed());
+        assert_eq!(algo.auth_rejected(), 0);
+    }
+
+    #[test]
+    fn unanswered_pings_trigger_failsafe() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        let mut reports = 0;
+        for t in 0..200 {
+            // Stick updates keep arriving, but pings go unanswered after 5 s
+            send(&mut algo, &mut actions, controls(0, 0, 0));
+            clock.advance(ms(100));
+            block_on(algo.step(&mut actions));
+            for msg in replies(&mut actions) {
+                match msg {
+                    MessageB2G::Ping(id) if t < 50 => {
+                        send(&mut algo, &mut actions, MessageG2B::Pong(id));
+                    }
+                    MessageB2G::LinkStats(_) => reports += 1,
+                    _ => {}
+                }
+            }
+            if t == 49 {
+                let stats = algo.link_stats();
+                assert_eq!(stats.packet_loss, Some(0.0));
+                assert!(stats.rtt_max.unwrap() <= 0.1);
+                assert_eq!(algo.failsafe_state(), FailsafeState::Nominal);
+            }
+        }
+        assert_eq!(reports, 20);
+        assert_eq!(algo.link_stats().packet_loss, Some(100.0));
+        assert!(matches!(algo.failsafe_state(), FailsafeState::Triggered(_)));
+    }
+
+    #[test]
+    fn hold_steers_to_target_which_sticks_nudge() {
+        let (mut algo, clock) = setup();
+        let mut actions = Vec::new();
+        feed_baro(&mut algo, &mut actions);
+        let heading = BlimpEvent::SensorDataF64(SensorType::Heading, 0.0);
+        block_on(algo.handle_event(&heading, &mut actions));
+        algo.set_flight_mode(FlightMode::StabilizeAttiAlti).unwrap();
+        block_on(algo.step(&mut actions));
+        let target_altitude = algo.hold.target_altitude.unwrap();
+        let target_heading = algo.hold.target_heading.unwrap();
+
+        // Target above and to the left of where the blimp is
+        algo.hold.target_altitude = Some(target_altitude + 2.0);
+        algo.hold.target_heading = Some(target_heading - 0.3);
+        actions.clear();
+        clock.advance(ms(20));
+        block_on(algo.step(&mut actions));
+        let servos = servo_locations(&mut actions);
+        assert_eq!(servos.len(), 8);
+        // Tilt servos (even) point thrust up, swivel servos (odd) turn left
+        assert!(servos.iter().all(|&(servo, location)| if servo % 2 == 0 {
+            location > 0
+        } else {
+            location < 0
+        }));
+
+        // Sticks move the targets rather than the outputs
+        for _ in 0..50 {
+            send(&mut algo, &mut actions, controls(0, 1000, 1000));
+            feed_baro(&mut algo, &mut actions);
+            clock.advance(ms(20));
+            block_on(algo.step(&mut actions));
+        }
+        let climbed = algo.hold.target_altitude.unwrap() - target_altitude - 2.0;
+        let turned = algo.hold.target_heading.unwrap() - target_heading + 0.3;
+        assert!((climbed - 1.0).abs() < 0.05, "{climbed}");
+        assert!((turned - 0.5).abs() < 0.05, "{turned}");
     }
 }
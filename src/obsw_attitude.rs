@@ -0,0 +1,202 @@
+// Mahony complementary filter estimating attitude from gyroscope, accelerometer and magnetometer
+// See: https://hal.science/hal-00488376/document
+//
+// Body frame is forward-right-down, earth frame is north-east-down, so yaw is the heading
+// clockwise from (magnetic) north.
+
+use crate::obsw_algo::Vector3;
+
+#[derive(Clone, Debug)]
+pub struct AttitudeConfig {
+    pub kp: f64, // Proportional correction gain (1/s)
+    pub ki: f64, // Gyro bias learning gain (1/s^2)
+}
+
+impl Default for AttitudeConfig {
+    fn default() -> Self {
+        Self { kp: 1.0, ki: 0.05 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Attitude {
+    pub roll: f64,  // Radians, right side down positive
+    pub pitch: f64, // Radians, nose up positive
+    pub yaw: f64,   // Radians, clockwise from north
+}
+
+#[derive(Clone, Debug)]
+pub struct AttitudeEstimator {
+    config: AttitudeConfig,
+    q: Option<[f64; 4]>, // Body to earth rotation, (w, x, y, z)
+    gyro_bias: Vector3,
+}
+
+impl AttitudeEstimator {
+    pub fn new(config: AttitudeConfig) -> Self {
+        Self {
+            config,
+            q: None,
+            gyro_bias: Vector3::default(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.q = None;
+        self.gyro_bias = Vector3::default();
+    }
+
+    pub fn attitude(&self) -> Option<Attitude> {
+        let [w, x, y, z] = self.q?;
+        Some(Attitude {
+            roll: (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y)),
+            pitch: (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin(),
+            yaw: (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z)),
+        })
+    }
+
+    // Rotates vector from body to earth frame
+    pub fn body_to_earth(&self, v: Vector3) -> Option<Vector3> {
+        self.q.map(|q| rotate(q, v))
+    }
+
+    // Gyro in rad/s, accelerometer in any units (specific force), magnetometer in any units
+    pub fn update(&mut self, gyro: Vector3, accel: Option<Vector3>, mag: Option<Vector3>, dt: f64) {
+        let accel = accel.and_then(Vector3::normalized);
+        let mag = mag.and_then(Vector3::normalized);
+        let Some(q) = self.q else {
+            // Start from the measured attitude rather than converging from level
+            if let Some(accel) = accel {
+                self.q = Some(initial_attitude(accel, mag));
+            }
+            return;
+        };
+        if dt <= 0.0 {
+            return;
+        }
+
+        let mut error = Vector3::default();
+        if let Some(accel) = accel {
+            // Specific force at rest points up, i.e. along -z of earth frame
+            let up = rotate(conjugate(q), Vector3::new(0.0, 0.0, -1.0));
+            error = error + accel.cross(up);
+        }
+        if let Some(mag) = mag {
+            // Reference field is the measured one rotated to earth, with horizontal part
+            // pointing north
+            let h = rotate(q, mag);
+            let b = Vector3::new((h.x * h.x + h.y * h.y).sqrt(), 0.0, h.z);
+            let expected = rotate(conjugate(q), b);
+            error = error + mag.cross(expected);
+        }
+
+        self.gyro_bias = self.gyro_bias - error * (self.config.ki * dt);
+        let omega = gyro - self.gyro_bias + error * self.config.kp;
+
+        let dq = multiply(q, [0.0, omega.x, omega.y, omega.z]);
+        let q = [
+            q[0] + 0.5 * dq[0] * dt,
+            q[1] + 0.5 * dq[1] * dt,
+            q[2] + 0.5 * dq[2] * dt,
+            q[3] + 0.5 * dq[3] * dt,
+        ];
+        let norm = q.iter().map(|v| v * v).sum::<f64>().sqrt();
+        self.q = Some(q.map(|v| v / norm));
+    }
+}
+
+fn initial_attitude(accel: Vector3, mag: Option<Vector3>) -> [f64; 4] {
+    let roll = (-accel.y).atan2(-accel.z);
+    let pitch = accel
+        .x
+        .atan2((accel.y * accel.y + accel.z * accel.z).sqrt());
+    let yaw = mag.map_or(0.0, |m| {
+        let (sr, cr) = roll.sin_cos();
+        let (sp, cp) = pitch.sin_cos();
+        let north = m.x * cp + m.y * sr * sp + m.z * cr * sp;
+        let east = -(m.y * cr - m.z * sr);
+        east.atan2(north)
+    });
+    let (sr, cr) = (roll / 2.0).sin_cos();
+    let (sp, cp) = (pitch / 2.0).sin_cos();
+    let (sy, cy) = (yaw / 2.0).sin_cos();
+    [
+        cr * cp * cy + sr * sp * sy,
+        sr * cp * cy - cr * sp * sy,
+        cr * sp * cy + sr * cp * sy,
+        cr * cp * sy - sr * sp * cy,
+    ]
+}
+
+fn multiply(a: [f64; 4], b: [f64; 4]) -> [f64; 4] {
+    [
+        a[0] * b[0] - a[1] * b[1] - a[2] * b[2] - a[3] * b[3],
+        a[0] * b[1] + a[1] * b[0] + a[2] * b[3] - a[3] * b[2],
+        a[0] * b[2] - a[1] * b[3] + a[2] * b[0] + a[3] * b[1],
+        a[0] * b[3] + a[1] * b[2] - a[2] * b[1] + a[3] * b[0],
+    ]
+}
+
+fn conjugate(q: [f64; 4]) -> [f64; 4] {
+    [q[0], -q[1], -q[2], -q[3]]
+}
+
+fn rotate(q: [f64; 4], v: Vector3) -> Vector3 {
+    let r = multiply(multiply(q, [0.0, v.x, v.y, v.z]), conjugate(q));
+    Vector3::new(r[1], r[2], r[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    const G: f64 = 9.81;
+
+    #[test]
+    fn initializes_from_accel_and_mag() {
+        let mut est = AttitudeEstimator::new(AttitudeConfig::default());
+        // Level, facing east - north is to the left
+        let mag = Vector3::new(0.0, -0.2, 0.4);
+        est.update(
+            Vector3::default(),
+            Some(Vector3::new(0.0, 0.0, -G)),
+            Some(mag),
+            0.01,
+        );
+        let att = est.attitude().unwrap();
+        assert!(att.roll.abs() < 1e-9 && att.pitch.abs() < 1e-9);
+        assert!((att.yaw - FRAC_PI_2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn tilt_is_recovered_from_accel() {
+        let mut est = AttitudeEstimator::new(AttitudeConfig::default());
+        let roll: f64 = 0.3;
+        let accel = Vector3::new(0.0, -G * roll.sin(), -G * roll.cos());
+        // Starts level, then converges despite a gyro bias
+        est.update(
+            Vector3::default(),
+            Some(Vector3::new(0.0, 0.0, -G)),
+            None,
+            0.01,
+        );
+        for _ in 0..3000 {
+            est.update(Vector3::new(0.01, 0.0, 0.0), Some(accel), None, 0.01);
+        }
+        let att = est.attitude().unwrap();
+        assert!((att.roll - roll).abs() < 0.01, "roll {}", att.roll);
+        assert!(att.pitch.abs() < 0.01);
+    }
+
+    #[test]
+    fn integrates_yaw_rate() {
+        let mut est = AttitudeEstimator::new(AttitudeConfig::default());
+        let accel = Vector3::new(0.0, 0.0, -G);
+        est.update(Vector3::default(), Some(accel), None, 0.01);
+        for _ in 0..100 {
+            est.update(Vector3::new(0.0, 0.0, 0.5), Some(accel), None, 0.01);
+        }
+        assert!((est.attitude().unwrap().yaw - 0.5).abs() < 1e-3);
+    }
+}
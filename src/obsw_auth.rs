@@ -0,0 +1,160 @@
+// Authentication of uplink messages with a key shared by the blimp and the ground station.
+//
+// Authenticated payload is counter (u32, big endian), message, then HMAC-SHA256 of session
+// (u64, big endian), counter and message truncated to TAG_LEN bytes. The session isn't sent,
+// it is the receiver's random id chosen on each start and announced in its Hello, so messages
+// recorded before a restart don't verify after it. Within a session each message uses a new
+// counter value and the receiver accepts each value only once, so recorded messages can't be
+// replayed either. A restarted sender learns where to continue counting from the receiver's
+// Hello, knowing it is of no use without the key.
+
+use crate::obsw_link::{Acceptance, SequenceWindow};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const COUNTER_LEN: usize = 4;
+pub const TAG_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthError {
+    TooShort,
+    BadTag,   // Wrong key or tampered message
+    Replayed, // Counter value already used
+}
+
+fn mac(key: &[u8], session: u64, data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&session.to_be_bytes());
+    mac.update(data);
+    mac
+}
+
+// Sending end, appends counter and tag
+pub struct MessageSigner {
+    key: Vec<u8>,
+    session: u64,
+    next_counter: u32,
+}
+
+impl MessageSigner {
+    // Session is the one announced by the receiver
+    pub fn new(key: &[u8], session: u64, first_counter: u32) -> Self {
+        Self {
+            key: key.to_vec(),
+            session,
+            next_counter: first_counter,
+        }
+    }
+
+    pub fn seal(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut data = self.next_counter.to_be_bytes().to_vec();
+        self.next_counter = self.next_counter.wrapping_add(1);
+        data.extend_from_slice(msg);
+        let tag = mac(&self.key, self.session, &data).finalize().into_bytes();
+        data.extend_from_slice(&tag[..TAG_LEN]);
+        data
+    }
+}
+
+// Receiving end, checks tag and counter
+pub struct MessageVerifier {
+    key: Vec<u8>,
+    session: u64,
+    counters: SequenceWindow,
+}
+
+impl MessageVerifier {
+    // Session must be new on every start, e.g. from obsw_link::new_session_id
+    pub fn new(key: &[u8], session: u64) -> Self {
+        Self {
+            key: key.to_vec(),
+            session,
+            counters: SequenceWindow::new(),
+        }
+    }
+
+    // Lowest counter value a sender starting now can use without its messages being replays
+    pub fn next_counter(&self) -> u32 {
+        self.counters
+            .newest()
+            .map_or(0, |newest| newest.wrapping_add(1))
+    }
+
+    // Returns the message without counter and tag
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, AuthError> {
+        if data.len() < COUNTER_LEN + TAG_LEN {
+            return Err(AuthError::TooShort);
+        }
+        let (signed, tag) = data.split_at(data.len() - TAG_LEN);
+        // Constant time comparison
+        mac(&self.key, self.session, signed)
+            .verify_truncated_left(tag)
+            .map_err(|_| AuthError::BadTag)?;
+        let counter = u32::from_be_bytes(signed[..COUNTER_LEN].try_into().unwrap());
+        match self.counters.check(counter) {
+            Acceptance::New | Acceptance::Late => {}
+            Acceptance::Duplicate | Acceptance::TooOld => return Err(AuthError::Replayed),
+        }
+        self.counters.record(counter);
+        Ok(signed[COUNTER_LEN..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_signed_and_rejects_tampered_or_replayed() {
+        let mut signer = MessageSigner::new(b"secret", 42, 7);
+        let mut verifier = MessageVerifier::new(b"secret", 42);
+        let first = signer.seal(b"arm");
+        let second = signer.seal(b"disarm");
+
+        assert_eq!(verifier.open(&second), Ok(b"disarm".to_vec()));
+        // Reordering is fine, replay is not
+        assert_eq!(verifier.open(&first), Ok(b"arm".to_vec()));
+        assert_eq!(verifier.open(&first), Err(AuthError::Replayed));
+
+        let mut tampered = signer.seal(b"arm");
+        tampered[5] ^= 1;
+        assert_eq!(verifier.open(&tampered), Err(AuthError::BadTag));
+        let forged = MessageSigner::new(b"guess", 42, 100).seal(b"arm");
+        assert_eq!(verifier.open(&forged), Err(AuthError::BadTag));
+        assert_eq!(verifier.open(&[0; 4]), Err(AuthError::TooShort));
+    }
+
+    #[test]
+    fn messages_of_other_session_are_rejected() {
+        let mut signer = MessageSigner::new(b"secret", 1, 0);
+        let recorded = signer.seal(b"arm");
+        assert!(MessageVerifier::new(b"secret", 1).open(&recorded).is_ok());
+        // Receiver restarted, counters start over but old messages don't verify
+        let mut verifier = MessageVerifier::new(b"secret", 2);
+        assert_eq!(verifier.open(&recorded), Err(AuthError::BadTag));
+        let mut signer = MessageSigner::new(b"secret", 2, 0);
+        assert!(verifier.open(&signer.seal(b"arm")).is_ok());
+    }
+
+    #[test]
+    fn restarted_sender_continues_at_next_counter() {
+        let mut verifier = MessageVerifier::new(b"secret", 1);
+        assert_eq!(verifier.next_counter(), 0);
+        let mut signer = MessageSigner::new(b"secret", 1, 500);
+        verifier.open(&signer.seal(b"arm")).unwrap();
+
+        let mut restarted = MessageSigner::new(b"secret", 1, 0);
+        assert_eq!(
+            verifier.open(&restarted.seal(b"disarm")),
+            Err(AuthError::Replayed)
+        );
+        let mut restarted = MessageSigner::new(b"secret", 1, verifier.next_counter());
+        assert_eq!(
+            verifier.open(&restarted.seal(b"disarm")),
+            Ok(b"disarm".to_vec())
+        );
+    }
+}
@@ -0,0 +1,278 @@
+// Kalman filter estimating altitude and vertical speed from barometer, GPS and accelerometer
+// See: https://en.wikipedia.org/wiki/Kalman_filter
+//
+// State is [altitude, vertical speed, barometer bias, GPS datum offset]. Altitude is kept in
+// the barometric reference frame (so QNH and zero calibration keep their meaning). Barometer
+// bias starts known (zero) and slowly random walks, GPS offset starts unknown and is constant,
+// so GPS first learns its datum and then corrects slow barometer drift.
+
+const N: usize = 4;
+type Vector = [f64; N];
+type Matrix = [[f64; N]; N];
+
+#[derive(Clone, Debug)]
+pub struct EstimatorConfig {
+    // Measurement std deviations - barometric and GPS altitude (m)
+    pub baro_noise: f64,
+    pub gps_noise: f64,
+    // Vertical acceleration std deviation with and without accelerometer data (m/s^2)
+    pub accel_noise: f64,
+    pub maneuver_noise: f64,
+    // Barometer bias random walk (m/sqrt(s))
+    pub baro_drift: f64,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        Self {
+            baro_noise: 0.5,
+            gps_noise: 4.0,
+            accel_noise: 0.3,
+            maneuver_noise: 1.0,
+            baro_drift: 0.05,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AltitudeEstimator {
+    config: EstimatorConfig,
+    state: Option<(Vector, Matrix)>,
+    gps_initialized: bool,
+}
+
+impl AltitudeEstimator {
+    pub fn new(config: EstimatorConfig) -> Self {
+        Self {
+            config,
+            state: None,
+            gps_initialized: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = None;
+        self.gps_initialized = false;
+    }
+
+    pub fn altitude(&self) -> Option<f64> {
+        self.state.map(|(x, _)| x[0])
+    }
+
+    pub fn vertical_speed(&self) -> Option<f64> {
+        self.state.map(|(x, _)| x[1])
+    }
+
+    pub fn altitude_variance(&self) -> Option<f64> {
+        self.state.map(|(_, p)| p[0][0])
+    }
+
+    pub fn vertical_speed_variance(&self) -> Option<f64> {
+        self.state.map(|(_, p)| p[1][1])
+    }
+
+    // Propagates estimate dt seconds forward, using vertical acceleration (up positive,
+    // gravity removed) when available
+    pub fn predict(&mut self, dt: f64, vertical_accel: Option<f64>) {
+        let Some((x, p)) = self.state.as_mut() else {
+            return;
+        };
+        if dt <= 0.0 {
+            return;
+        }
+        let accel = vertical_accel.unwrap_or(0.0);
+        let accel_var = match vertical_accel {
+            Some(_) => self.config.accel_noise.powi(2),
+            None => self.config.maneuver_noise.powi(2),
+        };
+
+        x[0] += x[1] * dt + 0.5 * accel * dt * dt;
+        x[1] += accel * dt;
+
+        // P = F P F^T + Q, F is identity except F[0][1] = dt
+        let mut fp = *p;
+        for j in 0..N {
+            fp[0][j] += dt * p[1][j];
+        }
+        let mut fpf = fp;
+        for row in fpf.iter_mut() {
+            row[0] += dt * row[1];
+        }
+        // Acceleration noise enters through G = [dt^2 / 2, dt, 0, 0]
+        let g = [0.5 * dt * dt, dt];
+        for i in 0..2 {
+            for j in 0..2 {
+                fpf[i][j] += g[i] * g[j] * accel_var;
+            }
+        }
+        fpf[2][2] += self.config.baro_drift.powi(2) * dt;
+        *p = fpf;
+    }
+
+    pub fn update_baro(&mut self, altitude: f64) {
+        // One non-finite measurement would make the state NaN for good
+        if !altitude.is_finite() {
+            return;
+        }
+        match self.state {
+            Some(_) => {
+                self.update(
+                    [1.0, 0.0, 1.0, 0.0],
+                    altitude,
+                    self.config.baro_noise.powi(2),
+                );
+            }
+            None => {
+                let baro_var = self.config.baro_noise.powi(2);
+                let mut p = [[0.0; N]; N];
+                p[0][0] = baro_var;
+                p[1][1] = 1.0;
+                // Bias is only observable relative to GPS, start fully trusting baro.
+                // GPS offset covariance is set on first GPS sample.
+                self.state = Some(([altitude, 0.0, 0.0, 0.0], p));
+            }
+        }
+    }
+
+    pub fn update_gps(&mut self, altitude: f64) {
+        if !altitude.is_finite() {
+            return;
+        }
+        let gps_var = self.config.gps_noise.powi(2);
+        let Some((x, p)) = self.state.as_mut() else {
+            // Barometer defines the frame, so wait for it
+            return;
+        };
+        if !self.gps_initialized {
+            self.gps_initialized = true;
+            x[3] = altitude - x[0];
+            p[3][3] = gps_var + p[0][0];
+            return;
+        }
+        self.update([1.0, 0.0, 0.0, 1.0], altitude, gps_var);
+    }
+
+    // Shifts the estimate when the barometric reference changes
+    pub fn offset(&mut self, delta: f64) {
+        if let Some((x, _)) = self.state.as_mut() {
+            x[0] += delta;
+            x[3] -= delta;
+        }
+    }
+
+    // Scalar measurement update z = H x + noise
+    fn update(&mut self, h: Vector, z: f64, r: f64) {
+        let Some((x, p)) = self.state.as_mut() else {
+            return;
+        };
+        let mut ph = [0.0; N]; // P H^T
+        for i in 0..N {
+            ph[i] = (0..N).map(|j| p[i][j] * h[j]).sum();
+        }
+        let s: f64 = (0..N).map(|i| h[i] * ph[i]).sum::<f64>() + r;
+        let k = ph.map(|v| v / s);
+        let innovation = z - (0..N).map(|i| h[i] * x[i]).sum::<f64>();
+        for i in 0..N {
+            x[i] += k[i] * innovation;
+        }
+        // P = P - K H P, symmetric since H P = (P H^T)^T
+        for i in 0..N {
+            for j in 0..N {
+                p[i][j] -= k[i] * ph[j];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 0.04;
+
+    #[test]
+    fn baro_only_follows_climb() {
+        let mut est = AltitudeEstimator::new(EstimatorConfig::default());
+        for i in 0..2000 {
+            est.predict(DT, None);
+            let noise = if i % 2 == 0 { 0.3 } else { -0.3 };
+            est.update_baro(i as f64 * DT * 0.5 + noise);
+        }
+        assert!((est.altitude().unwrap() - 1999.0 * DT * 0.5).abs() < 0.2);
+        assert!((est.vertical_speed().unwrap() - 0.5).abs() < 0.05);
+    }
+
+    #[test]
+    fn gps_corrects_baro_drift() {
+        let mut est = AltitudeEstimator::new(EstimatorConfig::default());
+        // Blimp hovers at 10 m, GPS reads it in its own datum, baro slowly drifts up
+        for i in 0..30000 {
+            let t = i as f64 * DT;
+            est.predict(DT, None);
+            est.update_baro(10.0 + 0.002 * t);
+            if i % 25 == 0 {
+                est.update_gps(110.0);
+            }
+        }
+        // Drift by now is 2.4 m
+        let altitude = est.altitude().unwrap();
+        assert!((altitude - 10.0).abs() < 0.5, "altitude {altitude}");
+    }
+
+    #[test]
+    fn gps_datum_is_averaged_not_sampled_once() {
+        let mut est = AltitudeEstimator::new(EstimatorConfig::default());
+        for i in 0..5000 {
+            est.predict(DT, None);
+            est.update_baro(10.0);
+            if i % 25 == 0 {
+                // First GPS sample is off by a lot
+                let noise = if i == 0 {
+                    8.0
+                } else if i % 50 == 0 {
+                    2.0
+                } else {
+                    -2.0
+                };
+                est.update_gps(110.0 + noise);
+            }
+        }
+        let altitude = est.altitude().unwrap();
+        assert!((altitude - 10.0).abs() < 0.3, "altitude {altitude}");
+    }
+
+    #[test]
+    fn accelerometer_tracks_speed_between_baro_samples() {
+        let mut est = AltitudeEstimator::new(EstimatorConfig::default());
+        est.update_baro(0.0);
+        for _ in 0..25 {
+            est.predict(DT, Some(1.0));
+        }
+        assert!((est.vertical_speed().unwrap() - 1.0).abs() < 1e-9);
+        assert!(est.altitude_variance().unwrap() > EstimatorConfig::default().baro_noise.powi(2));
+    }
+
+    #[test]
+    fn ignores_non_finite_measurements() {
+        let mut est = AltitudeEstimator::new(EstimatorConfig::default());
+        est.update_baro(f64::NAN);
+        assert_eq!(est.altitude(), None);
+        est.update_baro(10.0);
+        est.update_gps(110.0);
+        est.update_baro(f64::INFINITY);
+        est.update_gps(f64::NAN);
+        est.predict(DT, None);
+        est.update_gps(110.0);
+        assert!((est.altitude().unwrap() - 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn offset_keeps_gps_consistent() {
+        let mut est = AltitudeEstimator::new(EstimatorConfig::default());
+        est.update_baro(10.0);
+        est.update_gps(110.0);
+        est.offset(-10.0);
+        est.update_gps(110.0);
+        assert!(est.altitude().unwrap().abs() < 1e-6);
+    }
+}
@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FailsafeBehavior {
+    MotorsOff,
+    HoverHold,      // Hold current altitude and heading
+    ReturnToLaunch, // Fly back to launch position at current altitude
+}
+
+impl FailsafeBehavior {
+    // What to fall back to when this behavior isn't possible (e.g. no GPS for return)
+    fn fallback(self) -> Option<Self> {
+        match self {
+            FailsafeBehavior::ReturnToLaunch => Some(FailsafeBehavior::HoverHold),
+            FailsafeBehavior::HoverHold => Some(FailsafeBehavior::MotorsOff),
+            FailsafeBehavior::MotorsOff => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct FailsafeConfig {
+    pub timeout: f64, // Seconds without valid ground message before failsafe triggers
+    pub behavior: FailsafeBehavior,
+    pub max_packet_loss: f64, // %, link counts as lost above this even if messages arrive
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 2.0,
+            behavior: FailsafeBehavior::HoverHold,
+            max_packet_loss: 80.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum FailsafeState {
+    Nominal,
+    Triggered(FailsafeBehavior), // Behavior actually in effect, after fallbacks
+}
+
+// Tracks ground link liveness and decides failsafe behavior when it's lost
+#[derive(Clone, Debug)]
+pub struct Failsafe {
+    config: FailsafeConfig,
+    last_link: Option<Duration>,
+    packet_loss: Option<f64>,
+    state: FailsafeState,
+}
+
+impl Failsafe {
+    pub fn new(config: FailsafeConfig) -> Self {
+        Self {
+            config,
+            last_link: None,
+            packet_loss: None,
+            state: FailsafeState::Nominal,
+        }
+    }
+
+    pub fn config(&self) -> &FailsafeConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: FailsafeConfig) -> Result<(), String> {
+        if !(config.timeout.is_finite() && config.timeout > 0.0) {
+            return Err(format!("Invalid failsafe timeout {} s", config.timeout));
+        }
+        if !(0.0..=100.0).contains(&config.max_packet_loss) {
+            return Err(format!(
+                "Invalid maximum packet loss {} %",
+                config.max_packet_loss
+            ));
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn state(&self) -> FailsafeState {
+        self.state
+    }
+
+    // Call on every valid message from ground
+    pub fn link_alive(&mut self, now: Duration) {
+        self.last_link = Some(now);
+    }
+
+    pub fn link_established(&self) -> bool {
+        self.last_link.is_some()
+    }
+
+    // Measured packet loss (%), None when unknown
+    pub fn set_packet_loss(&mut self, loss: Option<f64>) {
+        self.packet_loss = loss;
+    }
+
+    pub fn link_lost(&self, now: Duration) -> bool {
+        // Nothing stale to act on before the first message
+        let timed_out = self
+            .last_link
+            .is_some_and(|last| now.saturating_sub(last).as_secs_f64() > self.config.timeout);
+        let lossy = self
+            .packet_loss
+            .is_some_and(|loss| loss > self.config.max_packet_loss);
+        timed_out || lossy
+    }
+
+    // Re-evaluates the state, `possible` tells whether a behavior can be performed right now.
+    // Returns new state on change.
+    pub fn update(
+        &mut self,
+        now: Duration,
+        possible: impl Fn(FailsafeBehavior) -> bool,
+    ) -> Option<FailsafeState> {
+        let state = if self.link_lost(now) {
+            let mut behavior = self.config.behavior;
+            while !possible(behavior) {
+                match behavior.fallback() {
+                    Some(fallback) => behavior = fallback,
+                    None => break,
+                }
+            }
+            FailsafeState::Triggered(behavior)
+        } else {
+            FailsafeState::Nominal
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triggers_after_timeout_and_recovers() {
+        let mut failsafe = Failsafe::new(FailsafeConfig::default());
+        let start = Duration::from_secs(100);
+        assert_eq!(
+            failsafe.update(start + Duration::from_secs(10), |_| true),
+            None
+        );
+
+        failsafe.link_alive(start);
+        assert_eq!(
+            failsafe.update(start + Duration::from_secs(1), |_| true),
+            None
+        );
+        assert_eq!(
+            failsafe.update(start + Duration::from_secs(3), |_| true),
+            Some(FailsafeState::Triggered(FailsafeBehavior::HoverHold))
+        );
+        assert_eq!(
+            failsafe.update(start + Duration::from_secs(4), |_| true),
+            None
+        );
+
+        failsafe.link_alive(start + Duration::from_secs(5));
+        assert_eq!(
+            failsafe.update(start + Duration::from_secs(5), |_| true),
+            Some(FailsafeState::Nominal)
+        );
+    }
+
+    #[test]
+    fn falls_back_when_behavior_is_impossible() {
+        let mut failsafe = Failsafe::new(FailsafeConfig {
+            timeout: 1.0,
+            behavior: FailsafeBehavior::ReturnToLaunch,
+            ..Default::default()
+        });
+        let start = Duration::from_secs(100);
+        failsafe.link_alive(start);
+        let later = start + Duration::from_secs(2);
+        assert_eq!(
+            failsafe.update(later, |b| b == FailsafeBehavior::MotorsOff),
+            Some(FailsafeState::Triggered(FailsafeBehavior::MotorsOff))
+        );
+        // Becomes possible again, e.g. GPS came back
+        assert_eq!(
+            failsafe.update(later, |_| true),
+            Some(FailsafeState::Triggered(FailsafeBehavior::ReturnToLaunch))
+        );
+    }
+
+    #[test]
+    fn triggers_on_packet_loss_while_messages_arrive() {
+        let mut failsafe = Failsafe::new(FailsafeConfig::default());
+        let now = Duration::from_secs(100);
+        failsafe.link_alive(now);
+        failsafe.set_packet_loss(Some(90.0));
+        assert_eq!(
+            failsafe.update(now, |_| true),
+            Some(FailsafeState::Triggered(FailsafeBehavior::HoverHold))
+        );
+        failsafe.set_packet_loss(Some(10.0));
+        assert_eq!(failsafe.update(now, |_| true), Some(FailsafeState::Nominal));
+    }
+
+    #[test]
+    fn rejects_invalid_timeout() {
+        let mut failsafe = Failsafe::new(FailsafeConfig::default());
+        let config = FailsafeConfig {
+            timeout: -1.0,
+            ..Default::default()
+        };
+        assert!(failsafe.set_config(config).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+// Framing of messages sent over the radio byte stream.
+//
+// Frame is the payload followed by its CRC-16 (big endian), COBS encoded and terminated by
+// a zero byte. COBS output never contains zero, so after corruption or a partial packet the
+// decoder resynchronizes on the next delimiter.
+// See: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
+
+pub const FRAME_DELIMITER: u8 = 0;
+// Longest accepted payload, longer frames are discarded
+pub const MAX_PAYLOAD_LEN: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameError {
+    Cobs,     // Invalid COBS encoding
+    TooShort, // Not even room for the CRC
+    TooLong,
+    Crc { expected: u16, actual: u16 },
+}
+
+// CRC-16/CCITT-FALSE - polynomial 0x1021, initial value 0xFFFF
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Returns complete frame, including the trailing delimiter
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&crc16(payload).to_be_bytes());
+    let mut frame = cobs_encode(&data);
+    frame.push(FRAME_DELIMITER);
+    frame
+}
+
+// Decodes one frame, with or without the trailing delimiter, returning its payload
+pub fn decode_frame(frame: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let frame = frame.strip_suffix(&[FRAME_DELIMITER]).unwrap_or(frame);
+    let mut data = cobs_decode(frame).ok_or(FrameError::Cobs)?;
+    if data.len() < 2 {
+        return Err(FrameError::TooShort);
+    }
+    let crc = data.split_off(data.len() - 2);
+    let expected = u16::from_be_bytes([crc[0], crc[1]]);
+    let actual = crc16(&data);
+    if expected != actual {
+        return Err(FrameError::Crc { expected, actual });
+    }
+    Ok(data)
+}
+
+// Splits a byte stream arriving in arbitrary chunks into frames
+#[derive(Clone, Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    overflow: bool, // Current frame is too long, skipping to next delimiter
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns payloads (or errors) of all frames completed by these bytes
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Result<Vec<u8>, FrameError>> {
+        // COBS adds a byte per 254, plus the CRC
+        let max_encoded = MAX_PAYLOAD_LEN + 2 + (MAX_PAYLOAD_LEN + 2) / 254 + 1;
+        let mut frames = Vec::new();
+        for &byte in bytes {
+            if byte == FRAME_DELIMITER {
+                if self.overflow {
+                    frames.push(Err(FrameError::TooLong));
+                } else if !self.buf.is_empty() {
+                    frames.push(decode_frame(&self.buf));
+                }
+                self.buf.clear();
+                self.overflow = false;
+            } else if self.buf.len() < max_encoded {
+                self.buf.push(byte);
+            } else {
+                self.overflow = true;
+            }
+        }
+        frames
+    }
+}
+
+fn cobs_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 254 + 1);
+    // Each block starts with its length + 1, the block ends where a zero was
+    let mut code_index = 0;
+    out.push(0);
+    for &byte in data {
+        if byte != 0 {
+            out.push(byte);
+        }
+        let len = out.len() - code_index;
+        if byte == 0 || len == 0xFF {
+            out[code_index] = len as u8;
+            code_index = out.len();
+            out.push(0);
+        }
+    }
+    out[code_index] = (out.len() - code_index) as u8;
+    out
+}
+
+fn cobs_decode(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let code = data[i] as usize;
+        if code == 0 || i + code > data.len() {
+            return None;
+        }
+        let block = &data[i + 1..i + code];
+        if block.contains(&0) {
+            return None;
+        }
+        out.extend_from_slice(block);
+        i += code;
+        // Maximum length blocks don't end with a zero, nor does the last one
+        if code < 0xFF && i < data.len() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_payloads_with_zeros_and_long_runs() {
+        let payloads: [Vec<u8>; 5] = [
+            vec![],
+            vec![0],
+            vec![0, 0, 1, 0],
+            (1..=255).collect(),
+            (0..600).map(|i| (i % 7) as u8).collect(),
+        ];
+        for payload in payloads {
+            let frame = encode_frame(&payload);
+            assert_eq!(frame.iter().filter(|&&b| b == 0).count(), 1);
+            assert_eq!(decode_frame(&frame), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn decoder_handles_split_merged_and_corrupt_frames() {
+        let mut stream = Vec::new();
+        stream.extend(encode_frame(b"first"));
+        let mut corrupt = encode_frame(b"second");
+        corrupt[3] ^= 0x40;
+        stream.extend(corrupt);
+        // Tail of a frame whose start was lost
+        stream.extend(&encode_frame(b"lost")[2..]);
+        stream.extend(encode_frame(b"third"));
+
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(3) {
+            frames.extend(decoder.push(chunk));
+        }
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0], Ok(b"first".to_vec()));
+        assert!(frames[1].is_err());
+        assert!(frames[2].is_err());
+        assert_eq!(frames[3], Ok(b"third".to_vec()));
+    }
+
+    #[test]
+    fn decoder_discards_overlong_frames() {
+        let mut decoder = FrameDecoder::new();
+        let mut frames = decoder.push(&vec![1; 2 * MAX_PAYLOAD_LEN]);
+        frames.extend(decoder.push(&[0]));
+        frames.extend(decoder.push(&encode_frame(b"ok")));
+        assert_eq!(frames, vec![Err(FrameError::TooLong), Ok(b"ok".to_vec())]);
+    }
+}
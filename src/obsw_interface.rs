@@ -1,7 +1,97 @@
-pub trait BlimpAlgorithm<EventType, ActionType> {
-    fn handle_event(
-        &mut self,
-        ev: &EventType,
-    ) -> std::pin::Pin<Box<impl std::future::Future<Output = ()>>>;
-    fn set_action_callback(&mut self, callback: Box<dyn Fn(ActionType) -> () + Send>);
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub type BoxFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+// Object safe, so algorithms can be swapped at runtime as `dyn BlimpAlgorithm`, and returning
+// Send futures, so they can run on multithreaded executors
+pub trait BlimpAlgorithm<EventType, ActionType>: Send {
+    // Every action caused by the event is emitted to `out` before the future completes
+    fn handle_event<'a>(
+        &'a mut self,
+        ev: &'a EventType,
+        out: &'a mut (dyn ActionSink<ActionType> + Send),
+    ) -> BoxFuture<'a>;
+    // Runs control loop once
+    fn step<'a>(&'a mut self, out: &'a mut (dyn ActionSink<ActionType> + Send)) -> BoxFuture<'a>;
+}
+
+// Receives actions produced by an algorithm, in the order they were produced
+pub trait ActionSink<ActionType> {
+    fn emit(&mut self, action: ActionType);
+}
+
+impl<ActionType> ActionSink<ActionType> for Vec<ActionType> {
+    fn emit(&mut self, action: ActionType) {
+        self.push(action);
+    }
+}
+
+impl<ActionType> ActionSink<ActionType> for VecDeque<ActionType> {
+    fn emit(&mut self, action: ActionType) {
+        self.push_back(action);
+    }
+}
+
+// Source of monotonic time since an arbitrary epoch (e.g. boot)
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+// Real time, counted from clock creation
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+// Time that only moves when told to, for simulation and tests
+#[derive(Default)]
+pub struct MockClock {
+    now: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new(start: Duration) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    // Going backwards is ignored, time stays monotonic
+    pub fn set(&self, time: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(time);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
 }
@@ -0,0 +1,436 @@
+// Sequencing of messages over the radio link.
+//
+// Every packet carries a sequence number increasing by one per packet sent. Unreliable packets
+// (e.g. stick controls) are latest-wins - anything not newer than the newest received is
+// dropped. Reliable packets are acknowledged by the receiver and retransmitted by the sender
+// until they are, the receiver applies each one once, even if it arrives out of order.
+
+use crate::obsw_auth::MessageSigner;
+use crate::obsw_framing::encode_frame;
+
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Packet<T> {
+    pub seq: u32,
+    pub reliable: bool, // Receiver must acknowledge with the sequence number
+    pub msg: T,
+}
+
+// Returns complete frame with the packet
+pub fn encode_packet<T: serde::Serialize>(packet: &Packet<T>) -> Vec<u8> {
+    encode_frame(&postcard::to_stdvec(packet).unwrap())
+}
+
+// Random identifier telling apart runs of the same software, e.g. before and after a restart
+pub fn new_session_id() -> u64 {
+    // Randomly keyed by the standard library from OS entropy
+    RandomState::new().build_hasher().finish()
+}
+
+const WINDOW_LEN: u32 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Acceptance {
+    New,       // Newer than anything received
+    Late,      // Older than newest received, but not seen yet
+    Duplicate, // Already received
+    TooOld,    // Beyond the window, can't tell whether it was received
+}
+
+// Remembers which of the last WINDOW_LEN sequence numbers were received
+#[derive(Clone, Debug, Default)]
+pub struct SequenceWindow {
+    newest: Option<u32>,
+    received: u64, // Bit n set when newest - n was received
+}
+
+impl SequenceWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn newest(&self) -> Option<u32> {
+        self.newest
+    }
+
+    pub fn check(&self, seq: u32) -> Acceptance {
+        let Some(newest) = self.newest else {
+            return Acceptance::New;
+        };
+        if seq > newest {
+            return Acceptance::New;
+        }
+        let age = newest - seq;
+        if age >= WINDOW_LEN {
+            Acceptance::TooOld
+        } else if self.received & (1 << age) != 0 {
+            Acceptance::Duplicate
+        } else {
+            Acceptance::Late
+        }
+    }
+
+    pub fn record(&mut self, seq: u32) {
+        match self.newest {
+            Some(newest) if seq <= newest => {
+                let age = newest - seq;
+                if age < WINDOW_LEN {
+                    self.received |= 1 << age;
+                }
+            }
+            newest => {
+                let shift = newest.map_or(WINDOW_LEN, |newest| seq - newest);
+                self.received = if shift >= WINDOW_LEN {
+                    0
+                } else {
+                    self.received << shift
+                };
+                self.received |= 1;
+                self.newest = Some(seq);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetransmitConfig {
+    pub timeout: f64, // s without acknowledgement before resending
+    pub max_attempts: u32,
+}
+
+impl Default for RetransmitConfig {
+    fn default() -> Self {
+        Self {
+            timeout: 0.5,
+            max_attempts: 5,
+        }
+    }
+}
+
+struct Unacked<T> {
+    packet: Packet<T>,
+    sent: Duration,
+    attempts: u32,
+}
+
+// Sending end of a link - numbers packets and keeps reliable ones until acknowledged
+pub struct ReliableSender<T> {
+    config: RetransmitConfig,
+    next_seq: u32,
+    unacked: Vec<Unacked<T>>,
+    signer: Option<MessageSigner>,
+}
+
+impl<T: Clone + serde::Serialize> ReliableSender<T> {
+    pub fn new(config: RetransmitConfig) -> Self {
+        Self {
+            config,
+            next_seq: 0,
+            unacked: Vec::new(),
+            signer: None,
+        }
+    }
+
+    // Authenticates every packet sent from now on, retransmissions included
+    pub fn set_signer(&mut self, signer: Option<MessageSigner>) {
+        self.signer = signer;
+    }
+
+    fn encode(&mut self, packet: &Packet<T>) -> Vec<u8> {
+        let payload = postcard::to_stdvec(packet).unwrap();
+        match &mut self.signer {
+            Some(signer) => encode_frame(&signer.seal(&payload)),
+            None => encode_frame(&payload),
+        }
+    }
+
+    // Returns frame to transmit
+    pub fn send(&mut self, msg: T, reliable: bool, now: Duration) -> Vec<u8> {
+        let packet = Packet {
+            seq: self.next_seq,
+            reliable,
+            msg,
+        };
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let frame = self.encode(&packet);
+        if reliable {
+            self.unacked.push(Unacked {
+                packet,
+                sent: now,
+                attempts: 1,
+            });
+        }
+        frame
+    }
+
+    // Returns whether the sequence number was waiting for acknowledgement
+    pub fn ack(&mut self, seq: u32) -> bool {
+        let len = self.unacked.len();
+        self.unacked.retain(|u| u.packet.seq != seq);
+        self.unacked.len() != len
+    }
+
+    // Returns frames to retransmit, and messages given up on after too many attempts
+    pub fn poll(&mut self, now: Duration) -> (Vec<Vec<u8>>, Vec<T>) {
+        let mut frames = Vec::new();
+        let mut failed = Vec::new();
+        let timeout = Duration::from_secs_f64(self.config.timeout);
+        let max_attempts = self.config.max_attempts;
+        let mut unacked = std::mem::take(&mut self.unacked);
+        unacked.retain_mut(|u| {
+            if now.saturating_sub(u.sent) < timeout {
+                return true;
+            }
+            if u.attempts >= max_attempts {
+                failed.push(u.packet.msg.clone());
+                return false;
+            }
+            // Same sequence number, so the receiver can tell it's a duplicate
+            frames.push(self.encode(&u.packet));
+            u.sent = now;
+            u.attempts += 1;
+            true
+        });
+        self.unacked = unacked;
+        (frames, failed)
+    }
+
+    pub fn pending(&self) -> usize {
+        self.unacked.len()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkMonitorConfig {
+    pub ping_period: f64,   // s
+    pub pong_timeout: f64,  // s after which a ping counts as lost
+    pub window: usize,      // Number of recent pings statistics are computed over
+    pub min_samples: usize, // Pings needed before packet loss is reported
+}
+
+impl Default for LinkMonitorConfig {
+    fn default() -> Self {
+        Self {
+            ping_period: 1.0,
+            pong_timeout: 2.0,
+            window: 10,
+            min_samples: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct LinkStats {
+    // Round trip times of recent answered pings (s)
+    pub rtt_min: Option<f64>,
+    pub rtt_avg: Option<f64>,
+    pub rtt_max: Option<f64>,
+    pub packet_loss: Option<f64>, // % of recent pings unanswered, None until enough were sent
+    pub last_seen: Option<f64>,   // s since last valid message from the other end
+}
+
+// Measures link quality with own pings, answered by pongs with the same id
+#[derive(Clone, Debug)]
+pub struct LinkMonitor {
+    config: LinkMonitorConfig,
+    next_id: u32,
+    next_ping: Option<Duration>,
+    outstanding: VecDeque<(u32, Duration)>, // Ping ids waiting for pong, and when sent
+    results: VecDeque<Option<f64>>,         // Round trip time of recent pings, None if lost
+    last_seen: Option<Duration>,
+}
+
+impl LinkMonitor {
+    pub fn new(config: LinkMonitorConfig) -> Self {
+        Self {
+            config,
+            next_id: 0,
+            next_ping: None,
+            outstanding: VecDeque::new(),
+            results: VecDeque::new(),
+            last_seen: None,
+        }
+    }
+
+    // Forgets statistics, e.g. when a new peer connects. Ping ids keep increasing, so late
+    // pongs to old pings aren't mistaken for new ones.
+    pub fn reset(&mut self) {
+        self.next_ping = None;
+        self.outstanding.clear();
+        self.results.clear();
+        self.last_seen = None;
+    }
+
+    // Call on every valid message from the other end
+    pub fn seen(&mut self, now: Duration) {
+        self.last_seen = Some(now);
+    }
+
+    // Expires unanswered pings, returns id of a new ping to send if one is due
+    pub fn poll(&mut self, now: Duration) -> Option<u32> {
+        let timeout = Duration::from_secs_f64(self.config.pong_timeout);
+        while let Some(&(_, sent)) = self.outstanding.front() {
+            if now.saturating_sub(sent) < timeout {
+                break;
+            }
+            self.outstanding.pop_front();
+            // Nothing at all arriving is an outage, left to the link timeout. Counting it as loss
+            // too would keep the link "lossy" for a whole window after it recovers.
+            if self.last_seen.is_some_and(|seen| seen > sent) {
+                self.record(None);
+            }
+        }
+
+        if self.next_ping.is_some_and(|next| now < next) {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.outstanding.push_back((id, now));
+        self.next_ping = Some(now + Duration::from_secs_f64(self.config.ping_period));
+        Some(id)
+    }
+
+    // Returns whether the pong answered an outstanding ping
+    pub fn pong(&mut self, id: u32, now: Duration) -> bool {
+        let Some(index) = self.outstanding.iter().position(|&(ping, _)| ping == id) else {
+            return false;
+        };
+        let (_, sent) = self.outstanding.remove(index).unwrap();
+        self.record(Some(now.saturating_sub(sent).as_secs_f64()));
+        true
+    }
+
+    fn record(&mut self, result: Option<f64>) {
+        self.results.push_back(result);
+        while self.results.len() > self.config.window {
+            self.results.pop_front();
+        }
+    }
+
+    pub fn stats(&self, now: Duration) -> LinkStats {
+        let rtts: Vec<f64> = self.results.iter().flatten().copied().collect();
+        let lost = self.results.len() - rtts.len();
+        LinkStats {
+            rtt_min: rtts.iter().copied().reduce(f64::min),
+            rtt_max: rtts.iter().copied().reduce(f64::max),
+            rtt_avg: (!rtts.is_empty()).then(|| rtts.iter().sum::<f64>() / rtts.len() as f64),
+            packet_loss: (self.results.len() >= self.config.min_samples)
+                .then(|| 100.0 * lost as f64 / self.results.len() as f64),
+            last_seen: self
+                .last_seen
+                .map(|seen| now.saturating_sub(seen).as_secs_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_classifies_sequence_numbers() {
+        let mut window = SequenceWindow::new();
+        assert_eq!(window.check(10), Acceptance::New);
+        window.record(10);
+        window.record(12);
+        assert_eq!(window.check(12), Acceptance::Duplicate);
+        assert_eq!(window.check(10), Acceptance::Duplicate);
+        assert_eq!(window.check(11), Acceptance::Late);
+        assert_eq!(window.check(13), Acceptance::New);
+        window.record(11);
+        assert_eq!(window.check(11), Acceptance::Duplicate);
+        window.record(100);
+        assert_eq!(window.check(12), Acceptance::TooOld);
+        assert_eq!(window.check(99), Acceptance::Late);
+    }
+
+    #[test]
+    fn retransmits_until_acknowledged_or_given_up() {
+        let mut sender = ReliableSender::new(RetransmitConfig {
+            timeout: 1.0,
+            max_attempts: 2,
+        });
+        let t = Duration::from_secs;
+        sender.send("control", false, t(0));
+        sender.send("arm", true, t(0));
+        sender.send("mode", true, t(0));
+        assert_eq!(sender.pending(), 2);
+        assert!(sender.ack(2));
+        assert!(!sender.ack(2));
+
+        let (frames, failed) = sender.poll(t(1));
+        assert_eq!(
+            frames,
+            vec![encode_packet(&Packet {
+                seq: 1,
+                reliable: true,
+                msg: "arm"
+            })]
+        );
+        assert!(failed.is_empty());
+        let (frames, failed) = sender.poll(t(2));
+        assert!(frames.is_empty());
+        assert_eq!(failed, vec!["arm"]);
+        assert_eq!(sender.pending(), 0);
+    }
+
+    #[test]
+    fn monitor_measures_round_trip_and_loss() {
+        let mut monitor = LinkMonitor::new(LinkMonitorConfig::default());
+        let ms = Duration::from_millis;
+        assert_eq!(monitor.poll(ms(0)), Some(0));
+        assert_eq!(monitor.poll(ms(500)), None);
+        assert!(monitor.pong(0, ms(100)));
+        assert!(!monitor.pong(0, ms(150)));
+
+        // Every other ping lost
+        for i in 1..10 {
+            let now = ms(1000 * i);
+            let id = monitor.poll(now).unwrap();
+            if i % 2 == 0 {
+                monitor.seen(now + ms(300));
+                monitor.pong(id, now + ms(300));
+            }
+        }
+        monitor.seen(ms(9000));
+        let stats = monitor.stats(ms(9500));
+        assert_eq!(stats.rtt_min, Some(0.1));
+        assert_eq!(stats.rtt_max, Some(0.3));
+        // Pings 1, 3, 5 and 7 timed out, 9 is still outstanding
+        assert_eq!(stats.packet_loss, Some(400.0 / 9.0));
+        assert_eq!(stats.last_seen, Some(0.5));
+    }
+
+    #[test]
+    fn pings_unanswered_during_outage_are_not_loss() {
+        let mut monitor = LinkMonitor::new(LinkMonitorConfig::default());
+        let ms = Duration::from_millis;
+        for i in 0..5 {
+            let now = ms(1000 * i);
+            let id = monitor.poll(now).unwrap();
+            monitor.seen(now + ms(100));
+            monitor.pong(id, now + ms(100));
+        }
+        // Nothing heard for 10 s, then the link is back
+        for i in 5..15 {
+            monitor.poll(ms(1000 * i));
+        }
+        for i in 15..20 {
+            let now = ms(1000 * i);
+            let id = monitor.poll(now).unwrap();
+            monitor.seen(now + ms(100));
+            monitor.pong(id, now + ms(100));
+        }
+        // Only ping 14 counts, it was still waiting when the link came back
+        assert_eq!(monitor.stats(ms(20000)).packet_loss, Some(10.0));
+    }
+}
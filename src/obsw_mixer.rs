@@ -0,0 +1,116 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize)]
+pub enum OutputKind {
+    Motor,
+    Servo,
+}
+
+// One actuator channel - its value is the weighted sum of the control axes
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct MixerOutput {
+    pub kind: OutputKind,
+    pub channel: u8,
+    pub throttle: f64,
+    pub elevation: f64,
+    pub yaw: f64,
+}
+
+impl MixerOutput {
+    pub fn new(kind: OutputKind, channel: u8, throttle: f64, elevation: f64, yaw: f64) -> Self {
+        Self {
+            kind,
+            channel,
+            throttle,
+            elevation,
+            yaw,
+        }
+    }
+}
+
+// Mixing table translating throttle/elevation/yaw commands to airframe's actuators
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Mixer {
+    pub outputs: Vec<MixerOutput>,
+}
+
+impl Mixer {
+    pub fn new(outputs: Vec<MixerOutput>) -> Self {
+        Self { outputs }
+    }
+
+    // Four motors with differential yaw, each on an up-down and a sideways vectoring servo
+    pub fn quad_vectored() -> Self {
+        let mut outputs = Vec::new();
+        for i in 0..4 {
+            let yaw_sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            outputs.push(MixerOutput::new(OutputKind::Motor, i, 1.0, 1.0, yaw_sign));
+            outputs.push(MixerOutput::new(OutputKind::Servo, 2 * i, 0.0, 1.0, 0.0));
+            outputs.push(MixerOutput::new(
+                OutputKind::Servo,
+                2 * i + 1,
+                0.0,
+                0.0,
+                1.0,
+            ));
+        }
+        Self::new(outputs)
+    }
+
+    // Two motors with differential yaw, tilted together by one servo each
+    pub fn twin_vectored() -> Self {
+        Self::new(vec![
+            MixerOutput::new(OutputKind::Motor, 0, 1.0, 1.0, 1.0),
+            MixerOutput::new(OutputKind::Motor, 1, 1.0, 1.0, -1.0),
+            MixerOutput::new(OutputKind::Servo, 0, 0.0, 1.0, 0.0),
+            MixerOutput::new(OutputKind::Servo, 1, 0.0, 1.0, 0.0),
+        ])
+    }
+
+    // Single pusher motor with elevator and rudder fins
+    pub fn tail_fin() -> Self {
+        Self::new(vec![
+            MixerOutput::new(OutputKind::Motor, 0, 1.0, 0.0, 0.0),
+            MixerOutput::new(OutputKind::Servo, 0, 0.0, 1.0, 0.0),
+            MixerOutput::new(OutputKind::Servo, 1, 0.0, 0.0, 1.0),
+        ])
+    }
+
+    // Returns (kind, channel, value) for every output, in table order
+    pub fn mix(&self, throttle: f64, elevation: f64, yaw: f64) -> Vec<(OutputKind, u8, f64)> {
+        self.outputs
+            .iter()
+            .map(|out| {
+                let value = out.throttle * throttle + out.elevation * elevation + out.yaw * yaw;
+                (out.kind, out.channel, value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quad_matches_legacy_mix() {
+        let mixed = Mixer::quad_vectored().mix(100.0, 20.0, 5.0);
+        assert_eq!(mixed.len(), 12);
+        assert_eq!(mixed[0], (OutputKind::Motor, 0, 125.0));
+        assert_eq!(mixed[1], (OutputKind::Servo, 0, 20.0));
+        assert_eq!(mixed[2], (OutputKind::Servo, 1, 5.0));
+        assert_eq!(mixed[3], (OutputKind::Motor, 1, 115.0));
+        assert_eq!(mixed[11], (OutputKind::Servo, 7, 5.0));
+    }
+
+    #[test]
+    fn tail_fin_separates_axes() {
+        let mixed = Mixer::tail_fin().mix(100.0, 20.0, 5.0);
+        assert_eq!(
+            mixed,
+            vec![
+                (OutputKind::Motor, 0, 100.0),
+                (OutputKind::Servo, 0, 20.0),
+                (OutputKind::Servo, 1, 5.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+#[derive(Clone, Debug)]
+pub struct PidConfig {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub output_min: f64,
+    pub output_max: f64,
+    // Maximum speed the internal setpoint follows the requested one with (units per second)
+    pub setpoint_rate_limit: Option<f64>,
+    // For angular quantities - errors are wrapped into -period/2..period/2
+    pub wrap_period: Option<f64>,
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        Self {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            output_min: f64::NEG_INFINITY,
+            output_max: f64::INFINITY,
+            setpoint_rate_limit: None,
+            wrap_period: None,
+        }
+    }
+}
+
+// PID controller with derivative on measurement, so setpoint changes don't kick the output
+#[derive(Clone, Debug)]
+pub struct Pid {
+    config: PidConfig,
+    integral: f64,
+    prev_measurement: Option<f64>,
+    setpoint: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(config: PidConfig) -> Self {
+        Self {
+            config,
+            integral: 0.0,
+            prev_measurement: None,
+            setpoint: None,
+        }
+    }
+
+    pub fn config(&self) -> &PidConfig {
+        &self.config
+    }
+
+    // Forget all accumulated state, e.g. on flight mode change
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.prev_measurement = None;
+        self.setpoint = None;
+    }
+
+    // Setpoint the controller is currently tracking (after ramping)
+    pub fn setpoint(&self) -> Option<f64> {
+        self.setpoint
+    }
+
+    pub fn update(&mut self, setpoint: f64, measurement: f64, dt: f64) -> f64 {
+        let measurement_rate = match self.prev_measurement {
+            Some(prev) if dt > 0.0 => self.wrap(measurement - prev) / dt,
+            _ => 0.0,
+        };
+        self.update_with_rate(setpoint, measurement, measurement_rate, dt)
+    }
+
+    // Like update, but with measurement derivative supplied from outside (e.g. a state estimator)
+    pub fn update_with_rate(
+        &mut self,
+        setpoint: f64,
+        measurement: f64,
+        measurement_rate: f64,
+        dt: f64,
+    ) -> f64 {
+        self.prev_measurement = Some(measurement);
+
+        let setpoint = match (self.setpoint, self.config.setpoint_rate_limit) {
+            (Some(prev), Some(rate)) => {
+                let max_change = rate * dt;
+                prev + self.wrap(setpoint - prev).clamp(-max_change, max_change)
+            }
+            _ => setpoint,
+        };
+        let setpoint = match self.config.wrap_period {
+            Some(_) => self.wrap(setpoint),
+            None => setpoint,
+        };
+        self.setpoint = Some(setpoint);
+
+        let error = self.wrap(setpoint - measurement);
+        let p_term = self.config.kp * error;
+        let d_term = -self.config.kd * measurement_rate;
+
+        // Anti-windup: don't integrate further into saturation, and never let the integral
+        // term alone exceed the output range
+        let unclamped = p_term + self.config.ki * self.integral + d_term;
+        let saturated_high = unclamped >= self.config.output_max && error > 0.0;
+        let saturated_low = unclamped <= self.config.output_min && error < 0.0;
+        if !saturated_high && !saturated_low {
+            self.integral += error * dt;
+        }
+        if self.config.ki != 0.0 {
+            let i_term = (self.config.ki * self.integral)
+                .clamp(self.config.output_min, self.config.output_max);
+            self.integral = i_term / self.config.ki;
+        }
+
+        (p_term + self.config.ki * self.integral + d_term)
+            .clamp(self.config.output_min, self.config.output_max)
+    }
+
+    fn wrap(&self, value: f64) -> f64 {
+        match self.config.wrap_period {
+            Some(period) => (value + period / 2.0).rem_euclid(period) - period / 2.0,
+            None => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // First-order lag plant: dx/dt = (gain * u - x) / tau
+    fn simulate(pid: &mut Pid, setpoint: f64, steps: usize, dt: f64) -> Vec<f64> {
+        let (gain, tau) = (2.0, 1.5);
+        let mut x = 0.0;
+        let mut trace = Vec::with_capacity(steps);
+        for _ in 0..steps {
+            let u = pid.update(setpoint, x, dt);
+            x += (gain * u - x) / tau * dt;
+            trace.push(x);
+        }
+        trace
+    }
+
+    fn config() -> PidConfig {
+        PidConfig {
+            kp: 1.5,
+            ki: 1.0,
+            kd: 0.1,
+            output_min: -5.0,
+            output_max: 5.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn step_response_settles_on_setpoint() {
+        let mut pid = Pid::new(config());
+        let trace = simulate(&mut pid, 1.0, 2000, 0.01);
+        let overshoot = trace.iter().cloned().fold(f64::MIN, f64::max) - 1.0;
+        assert!(overshoot < 0.2, "overshoot {overshoot}");
+        assert!((trace.last().unwrap() - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn proportional_only_leaves_steady_state_error() {
+        let mut pid = Pid::new(PidConfig {
+            ki: 0.0,
+            ..config()
+        });
+        let trace = simulate(&mut pid, 1.0, 2000, 0.01);
+        // Steady state of x = 2 * kp * (1 - x)
+        assert!((trace.last().unwrap() - 0.75).abs() < 1e-3);
+    }
+
+    #[test]
+    fn output_is_clamped() {
+        let mut pid = Pid::new(config());
+        assert_eq!(pid.update(100.0, 0.0, 0.01), 5.0);
+        assert_eq!(pid.update(-100.0, 0.0, 0.01), -5.0);
+    }
+
+    #[test]
+    fn integral_does_not_wind_up_while_saturated() {
+        let mut pid = Pid::new(PidConfig {
+            kp: 0.0,
+            kd: 0.0,
+            ..config()
+        });
+        for _ in 0..10000 {
+            pid.update(10.0, 0.0, 0.01);
+        }
+        // Once the error reverses, the output must leave saturation right away
+        assert!(pid.update(0.0, 10.0, 0.01) < 5.0);
+    }
+
+    #[test]
+    fn setpoint_change_does_not_kick_derivative() {
+        let mut pid = Pid::new(PidConfig {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 1.0,
+            ..config()
+        });
+        pid.update(0.0, 0.0, 0.01);
+        assert_eq!(pid.update(1.0, 0.0, 0.01), 0.0);
+        assert!(pid.update(1.0, 0.01, 0.01) < 0.0);
+    }
+
+    #[test]
+    fn setpoint_is_ramped() {
+        let mut pid = Pid::new(PidConfig {
+            setpoint_rate_limit: Some(0.5),
+            ..config()
+        });
+        pid.update(0.0, 0.0, 0.1);
+        pid.update(10.0, 0.0, 0.1);
+        assert!((pid.setpoint().unwrap() - 0.05).abs() < 1e-9);
+        for _ in 0..19 {
+            pid.update(10.0, 0.0, 0.1);
+        }
+        assert!((pid.setpoint().unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn wrapped_error_takes_shortest_way() {
+        let mut pid = Pid::new(PidConfig {
+            kp: 1.0,
+            ki: 0.0,
+            kd: 0.0,
+            wrap_period: Some(std::f64::consts::TAU),
+            ..Default::default()
+        });
+        let out = pid.update(3.0, -3.0, 0.01);
+        assert!((out - (6.0 - std::f64::consts::TAU)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn reset_clears_state() {
+        let mut pid = Pid::new(config());
+        simulate(&mut pid, 1.0, 100, 0.01);
+        pid.reset();
+        assert_eq!(pid.setpoint(), None);
+        assert_eq!(pid.update(0.0, 0.0, 0.01), 0.0);
+    }
+}
@@ -0,0 +1,249 @@
+// Host loop driving a BlimpAlgorithm on its own thread - events come in through a channel,
+// step runs at a fixed rate and all actions go out through another channel. The loop stops
+// when either channel is disconnected, so no action is ever dropped.
+
+use crate::obsw_interface::*;
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{JoinHandle, Thread};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct RuntimeConfig {
+    pub step_rate: f64, // Hz
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self { step_rate: 50.0 }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuntimeStats {
+    pub steps: u64,
+    pub events: u64,
+    pub overruns: u64, // Step periods skipped because the loop fell behind
+    pub max_step_duration: Duration,
+    pub max_lateness: Duration, // Worst delay of a step past its scheduled time
+}
+
+type Algorithm<E, A> = Box<dyn BlimpAlgorithm<E, A>>;
+
+pub struct Runtime<E, A> {
+    thread: JoinHandle<Algorithm<E, A>>,
+    shutdown: Arc<AtomicBool>,
+    stats: Arc<Mutex<RuntimeStats>>,
+}
+
+impl<E, A> Runtime<E, A>
+where
+    E: Send + Sync + 'static,
+    A: Send + 'static,
+{
+    // Runs until shutdown is called or either channel is disconnected
+    pub fn spawn(
+        algo: Algorithm<E, A>,
+        config: RuntimeConfig,
+        events: Receiver<E>,
+        actions: Sender<A>,
+    ) -> Result<Self, String> {
+        if !(config.step_rate.is_finite() && config.step_rate > 0.0) {
+            return Err(format!("Invalid step rate {} Hz", config.step_rate));
+        }
+        let period = Duration::from_secs_f64(1.0 / config.step_rate);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(Mutex::new(RuntimeStats::default()));
+        let thread = std::thread::Builder::new()
+            .name("blimp-runtime".into())
+            .spawn({
+                let shutdown = shutdown.clone();
+                let stats = stats.clone();
+                move || run(algo, period, events, actions, &shutdown, &stats)
+            })
+            .map_err(|e| format!("Can't spawn runtime thread: {e}"))?;
+        Ok(Self {
+            thread,
+            shutdown,
+            stats,
+        })
+    }
+
+    pub fn stats(&self) -> RuntimeStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.thread.is_finished()
+    }
+
+    // Stops the loop after the event or step in progress, returning the algorithm
+    pub fn shutdown(self) -> (Algorithm<E, A>, RuntimeStats) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        let algo = self.thread.join().expect("Runtime thread panicked");
+        let stats = self.stats.lock().unwrap().clone();
+        (algo, stats)
+    }
+}
+
+// Polls the future to completion on the current thread, parking it while the future waits
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unparker(Thread);
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = Waker::from(Arc::new(Unparker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+// Forwards actions to the channel, remembering if the receiver hung up
+struct ChannelSink<A> {
+    sender: Sender<A>,
+    disconnected: bool,
+}
+
+impl<A> ActionSink<A> for ChannelSink<A> {
+    fn emit(&mut self, action: A) {
+        if self.sender.send(action).is_err() {
+            self.disconnected = true;
+        }
+    }
+}
+
+fn run<E, A>(
+    mut algo: Algorithm<E, A>,
+    period: Duration,
+    events: Receiver<E>,
+    actions: Sender<A>,
+    shutdown: &AtomicBool,
+    stats: &Mutex<RuntimeStats>,
+) -> Algorithm<E, A>
+where
+    E: Send + Sync,
+    A: Send,
+{
+    let mut actions = ChannelSink {
+        sender: actions,
+        disconnected: false,
+    };
+    let mut next_step = Instant::now();
+    while !shutdown.load(Ordering::Relaxed) && !actions.disconnected {
+        let timeout = next_step.saturating_duration_since(Instant::now());
+        match events.recv_timeout(timeout) {
+            Ok(ev) => {
+                block_on(algo.handle_event(&ev, &mut actions));
+                stats.lock().unwrap().events += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let start = Instant::now();
+        if start < next_step || actions.disconnected {
+            continue;
+        }
+        block_on(algo.step(&mut actions));
+        let end = Instant::now();
+
+        let mut stats = stats.lock().unwrap();
+        stats.steps += 1;
+        stats.max_step_duration = stats.max_step_duration.max(end - start);
+        stats.max_lateness = stats.max_lateness.max(start - next_step);
+        next_step += period;
+        if next_step <= end {
+            // Skip missed periods instead of running them back to back
+            let missed = ((end - next_step).as_secs_f64() / period.as_secs_f64()) as u64 + 1;
+            stats.overruns += missed;
+            next_step += period * missed as u32;
+        }
+    }
+    algo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obsw_algo::*;
+    use std::sync::mpsc;
+
+    // Polls the condition until it holds or a generous deadline passes, so the tests don't
+    // depend on how loaded the machine is
+    fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    fn spawn(
+        events: Receiver<BlimpEvent>,
+        actions: Sender<BlimpAction>,
+    ) -> Runtime<BlimpEvent, BlimpAction> {
+        let config = RuntimeConfig { step_rate: 100.0 };
+        Runtime::spawn(
+            Box::new(BlimpMainAlgo::new(b"key")),
+            config,
+            events,
+            actions,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn steps_and_shuts_down() {
+        let (event_tx, event_rx) = mpsc::channel();
+        let (action_tx, action_rx) = mpsc::channel();
+        let runtime = spawn(event_rx, action_tx);
+        event_tx
+            .send(BlimpEvent::SensorDataF64(SensorType::Barometer, 100000.0))
+            .unwrap();
+        assert!(wait_until(|| {
+            let stats = runtime.stats();
+            stats.events == 1 && stats.steps >= 3
+        }));
+        let (_algo, stats) = runtime.shutdown();
+
+        assert_eq!(stats.events, 1);
+        // Every step sets all four motors, none of it is lost
+        let motor_actions = action_rx
+            .try_iter()
+            .filter(|action| matches!(action, BlimpAction::SetMotor { .. }))
+            .count() as u64;
+        assert_eq!(motor_actions, 4 * stats.steps);
+    }
+
+    #[test]
+    fn stops_when_event_channel_disconnects() {
+        let (event_tx, event_rx) = mpsc::channel();
+        let (action_tx, _action_rx) = mpsc::channel();
+        let runtime = spawn(event_rx, action_tx);
+        drop(event_tx);
+        assert!(wait_until(|| !runtime.is_running()));
+    }
+
+    #[test]
+    fn stops_when_action_receiver_hangs_up() {
+        let (_event_tx, event_rx) = mpsc::channel();
+        let (action_tx, action_rx) = mpsc::channel();
+        let runtime = spawn(event_rx, action_tx);
+        drop(action_rx);
+        assert!(wait_until(|| !runtime.is_running()));
+    }
+}
@@ -0,0 +1,420 @@
+// Scripted simulation runs - a scenario describes the initial state, wind, ground commands and
+// sensor faults over time, plus expectations checked along the way. Running one produces a
+// trace of simulated state and actuator outputs, usable both from the blimp_sim binary and
+// from regression tests.
+
+use crate::obsw_algo::*;
+use crate::obsw_failsafe::FailsafeState;
+use crate::obsw_sim::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+// Both ends of the simulated link share it, scenarios are about flight rather than security
+const AUTH_KEY: &[u8] = b"scenario";
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Scenario {
+    pub duration: f64,     // s
+    pub step: f64,         // s, simulation and algorithm step
+    pub heartbeat: f64,    // s between pings keeping the ground link alive, 0 disables
+    pub trace_period: f64, // s between trace rows, 0 records every step
+    pub sim: SimConfig,
+    pub initial: SimState,
+    pub wind: Vec<WindChange>,
+    pub commands: Vec<Command>,
+    pub faults: Vec<Fault>,
+    pub expect: Vec<Expectation>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            duration: 60.0,
+            step: 0.02,
+            heartbeat: 0.5,
+            trace_period: 0.1,
+            sim: SimConfig::default(),
+            initial: SimState::default(),
+            wind: Vec::new(),
+            commands: Vec::new(),
+            faults: Vec::new(),
+            expect: Vec::new(),
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {e}", path.display()))?;
+        serde_json::from_str(&text).map_err(|e| format!("Invalid scenario {}: {e}", path.display()))
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct WindChange {
+    pub time: f64,
+    pub wind: Wind,
+}
+
+// Message sent from ground at given time, lost if the link is down then
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Command {
+    pub time: f64,
+    pub message: MessageG2B,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Fault {
+    pub time: f64,
+    pub fault: FaultKind,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub enum FaultKind {
+    GpsDropout(f64), // s without GPS fix
+    LinkLoss(f64),   // s without any message in either direction
+    Event(BlimpEvent),
+}
+
+// Conditions checked once simulation time reaches `time`, unset ones are ignored
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Expectation {
+    pub time: f64,
+    pub altitude: Option<(f64, f64)>, // Range of true altitude above ground (m)
+    pub max_distance: Option<f64>,    // Horizontal distance from origin (m)
+    pub armed: Option<bool>,
+    pub flight_mode: Option<FlightMode>,
+    pub failsafe: Option<FailsafeState>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct TraceRow {
+    pub state: SimState,
+    pub altitude_estimate: Option<f64>, // m above sea level
+    pub vertical_speed_estimate: Option<f64>,
+    pub heading_estimate: Option<f64>,
+    pub flight_mode: FlightMode,
+    pub armed: bool,
+    pub failsafe: FailsafeState,
+    pub motors: BTreeMap<u8, i32>,
+    pub servos: BTreeMap<u8, i16>,
+    pub downlink: Vec<MessageB2G>, // Sent since previous row
+}
+
+#[derive(Clone, Debug)]
+pub struct ScenarioResult {
+    pub trace: Vec<TraceRow>,
+    pub failures: Vec<String>,
+}
+
+impl ScenarioResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub fn run_scenario(scenario: &Scenario) -> Result<ScenarioResult, String> {
+    if !(scenario.step.is_finite() && scenario.step > 0.0) {
+        return Err(format!("Invalid step {}", scenario.step));
+    }
+    if !scenario.duration.is_finite() {
+        return Err(format!("Invalid duration {}", scenario.duration));
+    }
+    // Simulated clock starts at initial time and can't go negative
+    if !(scenario.initial.time.is_finite() && scenario.initial.time >= 0.0) {
+        return Err(format!("Invalid initial time {}", scenario.initial.time));
+    }
+    let config = &scenario.sim;
+    for (sensor, rate) in [
+        ("barometer", config.baro_rate),
+        ("GPS", config.gps_rate),
+        ("IMU", config.imu_rate),
+    ] {
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err(format!("Invalid {sensor} rate {rate} Hz"));
+        }
+    }
+
+    let sim = Simulator::new(scenario.sim.clone(), scenario.initial.clone());
+    let mut simulation = Simulation::new(BlimpMainAlgo::new(AUTH_KEY), sim, AUTH_KEY);
+    let start = simulation.time();
+    let end = start + scenario.duration;
+
+    let mut wind = scenario.wind.clone();
+    let mut commands = scenario.commands.clone();
+    let mut faults = scenario.faults.clone();
+    let mut expect = scenario.expect.clone();
+    wind.sort_by(|a, b| a.time.total_cmp(&b.time));
+    commands.sort_by(|a, b| a.time.total_cmp(&b.time));
+    faults.sort_by(|a, b| a.time.total_cmp(&b.time));
+    expect.sort_by(|a, b| a.time.total_cmp(&b.time));
+    let (mut wind, mut commands, mut faults, mut expect) = (
+        wind.into_iter().peekable(),
+        commands.into_iter().peekable(),
+        faults.into_iter().peekable(),
+        expect.into_iter().peekable(),
+    );
+
+    let mut link_down_until = f64::NEG_INFINITY;
+    let mut gps_down_until = f64::NEG_INFINITY;
+    let mut next_heartbeat = start;
+    let mut ping_id = 0;
+    let mut next_trace = start;
+    let mut motors = BTreeMap::new();
+    let mut servos = BTreeMap::new();
+    let mut downlink = Vec::new();
+    let mut trace = Vec::new();
+    let mut failures = Vec::new();
+
+    // Half a step of slack, so accumulated rounding doesn't add an extra step
+    while simulation.time() < end - scenario.step / 2.0 {
+        let t = simulation.time();
+        while let Some(change) = wind.next_if(|c| c.time <= t) {
+            simulation.sim.set_wind(change.wind);
+        }
+        while let Some(fault) = faults.next_if(|f| f.time <= t) {
+            match fault.fault {
+                FaultKind::GpsDropout(duration) => gps_down_until = t + duration,
+                FaultKind::LinkLoss(duration) => link_down_until = t + duration,
+                FaultKind::Event(ev) => simulation.inject_event(ev),
+            }
+        }
+        simulation.sim.set_gps_dropout(t < gps_down_until);
+        simulation.set_link_down(t < link_down_until);
+        while let Some(command) = commands.next_if(|c| c.time <= t) {
+            simulation.uplink(&command.message);
+        }
+        if scenario.heartbeat > 0.0 && t >= next_heartbeat {
+            next_heartbeat += scenario.heartbeat;
+            simulation.uplink(&MessageG2B::Ping(ping_id));
+            ping_id = ping_id.wrapping_add(1);
+        }
+
+        for action in simulation.tick(scenario.step) {
+            match action {
+                BlimpAction::SetMotor { motor, speed } => {
+                    motors.insert(motor, speed);
+                }
+                BlimpAction::SetServo { servo, location } => {
+                    servos.insert(servo, location);
+                }
+                BlimpAction::SendMsg(_) => {}
+            }
+        }
+        downlink.append(&mut simulation.take_downlink());
+
+        let t = simulation.time();
+        while let Some(expectation) = expect.next_if(|e| e.time <= t) {
+            failures.extend(check(&expectation, &simulation));
+        }
+        if t >= next_trace {
+            next_trace += scenario.trace_period;
+            // Don't fall behind when trace period is shorter than the step
+            next_trace = next_trace.max(t);
+            let algo = &simulation.algo;
+            trace.push(TraceRow {
+                state: simulation.sim.state().clone(),
+                altitude_estimate: algo.altitude(),
+                vertical_speed_estimate: algo.vertical_speed(),
+                heading_estimate: algo.heading(),
+                flight_mode: algo.flight_mode(),
+                armed: algo.armed(),
+                failsafe: algo.failsafe_state(),
+                motors: motors.clone(),
+                servos: servos.clone(),
+                downlink: std::mem::take(&mut downlink),
+            });
+        }
+    }
+
+    for expectation in expect {
+        failures.push(format!(
+            "t={:.2}: expectation beyond scenario end",
+            expectation.time
+        ));
+    }
+    Ok(ScenarioResult { trace, failures })
+}
+
+fn check(expectation: &Expectation, simulation: &Simulation) -> Vec<String> {
+    let state = simulation.sim.state();
+    let algo = &simulation.algo;
+    let mut failures = Vec::new();
+    let mut fail = |what: String| failures.push(format!("t={:.2}: {what}", state.time));
+
+    if let Some((min, max)) = expectation.altitude {
+        if !(min..=max).contains(&state.altitude) {
+            fail(format!(
+                "altitude {:.2} m outside {min}..{max}",
+                state.altitude
+            ));
+        }
+    }
+    if let Some(max) = expectation.max_distance {
+        let distance = state.north.hypot(state.east);
+        if distance > max {
+            fail(format!("distance {distance:.2} m from origin above {max}"));
+        }
+    }
+    if let Some(armed) = expectation.armed {
+        if algo.armed() != armed {
+            fail(format!("armed is {}, expected {armed}", algo.armed()));
+        }
+    }
+    if let Some(mode) = expectation.flight_mode {
+        if algo.flight_mode() != mode {
+            fail(format!(
+                "flight mode {:?}, expected {mode:?}",
+                algo.flight_mode()
+            ));
+        }
+    }
+    if let Some(state) = expectation.failsafe {
+        if algo.failsafe_state() != state {
+            fail(format!(
+                "failsafe {:?}, expected {state:?}",
+                algo.failsafe_state()
+            ));
+        }
+    }
+    failures
+}
+
+pub fn write_json(trace: &[TraceRow], out: &mut impl Write) -> std::io::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, trace)?;
+    writeln!(out)
+}
+
+// One column per value, actuator channels are the union of those seen in the whole trace.
+// Downlink messages are only part of the JSON trace.
+pub fn write_csv(trace: &[TraceRow], out: &mut impl Write) -> std::io::Result<()> {
+    let motors: BTreeSet<u8> = trace
+        .iter()
+        .flat_map(|r| r.motors.keys().copied())
+        .collect();
+    let servos: BTreeSet<u8> = trace
+        .iter()
+        .flat_map(|r| r.servos.keys().copied())
+        .collect();
+    let opt = |v: Option<f64>| v.map_or(String::new(), |v| format!("{v:.4}"));
+
+    write!(
+        out,
+        "time,north,east,altitude,velocity_north,velocity_east,velocity_up,heading,yaw_rate,\
+         altitude_estimate,vertical_speed_estimate,heading_estimate,flight_mode,armed,failsafe"
+    )?;
+    for m in &motors {
+        write!(out, ",motor{m}")?;
+    }
+    for s in &servos {
+        write!(out, ",servo{s}")?;
+    }
+    writeln!(out)?;
+
+    for row in trace {
+        let s = &row.state;
+        write!(
+            out,
+            "{:.3},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{},{},{},{:?},{},{:?}",
+            s.time,
+            s.north,
+            s.east,
+            s.altitude,
+            s.velocity.0,
+            s.velocity.1,
+            s.velocity.2,
+            s.heading,
+            s.yaw_rate,
+            opt(row.altitude_estimate),
+            opt(row.vertical_speed_estimate),
+            opt(row.heading_estimate),
+            row.flight_mode,
+            row.armed,
+            row.failsafe,
+        )?;
+        for m in &motors {
+            write!(out, ",{}", row.motors.get(m).copied().unwrap_or(0))?;
+        }
+        for s in &servos {
+            write!(out, ",{}", row.servos.get(s).copied().unwrap_or(0))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_minimal_scenario_with_defaults() {
+        let scenario: Scenario = serde_json::from_str(
+            r#"{
+                "duration": 2.0,
+                "initial": { "altitude": 10.0 },
+                "commands": [ { "time": 0.5, "message": "Arm" } ],
+                "faults": [ { "time": 1.0, "fault": { "LinkLoss": 5.0 } } ],
+                "expect": [ { "time": 1.5, "altitude": [5.0, 15.0] } ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(scenario.step, 0.02);
+        assert_eq!(scenario.initial.altitude, 10.0);
+        assert_eq!(scenario.sim.motors.len(), 4);
+    }
+
+    #[test]
+    fn rejects_invalid_initial_time_and_sensor_rates() {
+        let negative_time = Scenario {
+            initial: SimState {
+                time: -1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(run_scenario(&negative_time).is_err());
+        for rate in [0.0, -5.0, f64::NAN] {
+            let scenario = Scenario {
+                sim: SimConfig {
+                    gps_rate: rate,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            assert!(run_scenario(&scenario).is_err());
+        }
+    }
+
+    #[test]
+    fn reports_failed_and_unreached_expectations() {
+        let scenario = Scenario {
+            duration: 1.0,
+            initial: SimState {
+                altitude: 10.0,
+                ..Default::default()
+            },
+            expect: vec![
+                Expectation {
+                    time: 0.5,
+                    altitude: Some((9.0, 11.0)),
+                    armed: Some(true),
+                    ..Default::default()
+                },
+                Expectation {
+                    time: 5.0,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let result = run_scenario(&scenario).unwrap();
+        assert_eq!(result.failures.len(), 2, "{:?}", result.failures);
+        assert!(result.failures[0].contains("armed"));
+        assert!((result.trace.len() as i32 - 10).abs() <= 1);
+    }
+}
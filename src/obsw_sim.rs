@@ -0,0 +1,553 @@
+// Software-in-the-loop simulation - simple blimp physics driven by BlimpActions, producing
+// synthetic sensor BlimpEvents, so BlimpMainAlgo can fly closed loop without the airframe.
+//
+// Model: point mass with net buoyancy, quadratic drag relative to air, motor thrust vectored
+// by servos, and yaw rotation only (the envelope is assumed to stay level).
+
+use crate::obsw_algo::*;
+use crate::obsw_auth::MessageSigner;
+use crate::obsw_framing::FrameDecoder;
+use crate::obsw_interface::*;
+use crate::obsw_link::{new_session_id, ReliableSender, RetransmitConfig};
+use crate::obsw_runtime::block_on;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Gravity (m/s^2) and earth radius (m)
+const GRAVITY: f64 = 9.80665;
+const EARTH_RADIUS: f64 = 6371000.0;
+// Standard atmosphere used to produce barometer readings (Pa, K, R / g / M)
+const STANDARD_PRESSURE: f64 = 101325.0;
+const STANDARD_TEMPERATURE: f64 = 288.15;
+const BARO_COEF: f64 = 0.0292718;
+// Earth magnetic field direction (north, down), magnitude is irrelevant
+const MAG_FIELD: (f64, f64) = (0.2, 0.4);
+
+// Motor mounted on the gondola, optionally tilted up (tilt) and sideways (swivel) by servos
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct SimMotor {
+    pub motor: u8,
+    pub forward_offset: f64, // m ahead of center of mass
+    pub right_offset: f64,   // m right of center of mass
+    pub tilt_servo: Option<u8>,
+    pub swivel_servo: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Wind {
+    pub north: f64,          // m/s, direction the air moves to
+    pub east: f64,           // m/s
+    pub gust_amplitude: f64, // m/s, added along mean wind direction
+    pub gust_period: f64,    // s
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SimConfig {
+    pub mass: f64,            // kg, including air moved along with the envelope
+    pub net_buoyancy: f64,    // N, lift minus weight
+    pub drag_horizontal: f64, // N per (m/s)^2
+    pub drag_vertical: f64,   // N per (m/s)^2
+    pub yaw_inertia: f64,     // kg m^2
+    pub yaw_damping: f64,     // N m per rad/s
+    pub thrust_per_unit: f64, // N per SetMotor speed unit
+    pub servo_rad_per_unit: f64,
+    pub motors: Vec<SimMotor>,
+    pub wind: Wind,
+    // Launch site - latitude, longitude (degrees) and ground altitude (m above sea level)
+    pub origin: (f64, f64, f64),
+    // Sensor sample rates (Hz) and noise std deviations (m, m, rad/s)
+    pub baro_rate: f64,
+    pub gps_rate: f64,
+    pub imu_rate: f64,
+    pub baro_noise: f64,
+    pub gps_noise: f64,
+    pub gyro_noise: f64,
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    // Four vectored motors, matching Mixer::quad_vectored
+    fn default() -> Self {
+        let motors = (0..4)
+            .map(|i| SimMotor {
+                motor: i,
+                forward_offset: 0.5,
+                // Even motors get positive yaw, so they push from the left side
+                right_offset: if i % 2 == 0 { -0.3 } else { 0.3 },
+                tilt_servo: Some(2 * i),
+                swivel_servo: Some(2 * i + 1),
+            })
+            .collect();
+        Self {
+            mass: 2.0,
+            net_buoyancy: -0.05,
+            drag_horizontal: 0.5,
+            drag_vertical: 1.0,
+            yaw_inertia: 0.5,
+            yaw_damping: 0.3,
+            thrust_per_unit: 0.0005,
+            servo_rad_per_unit: std::f64::consts::FRAC_PI_2 / 1000.0,
+            motors,
+            wind: Wind::default(),
+            origin: (50.0, 20.0, 200.0),
+            baro_rate: 25.0,
+            gps_rate: 5.0,
+            imu_rate: 50.0,
+            baro_noise: 0.2,
+            gps_noise: 1.0,
+            gyro_noise: 0.002,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct SimState {
+    pub time: f64,                 // s
+    pub north: f64,                // m from origin
+    pub east: f64,                 // m from origin
+    pub altitude: f64,             // m above ground
+    pub velocity: (f64, f64, f64), // m/s north, east, up
+    pub heading: f64,              // rad, clockwise from north
+    pub yaw_rate: f64,             // rad/s
+}
+
+pub struct Simulator {
+    config: SimConfig,
+    state: SimState,
+    motor_speeds: HashMap<u8, i32>,
+    servo_locations: HashMap<u8, i16>,
+    rng: Rng,
+    acceleration: (f64, f64, f64), // Last computed, for accelerometer
+    next_baro: f64,
+    next_gps: f64,
+    next_imu: f64,
+    gps_dropout: bool,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig, state: SimState) -> Self {
+        let rng = Rng::new(config.seed);
+        Self {
+            config,
+            next_baro: state.time,
+            next_gps: state.time,
+            next_imu: state.time,
+            state,
+            motor_speeds: HashMap::new(),
+            servo_locations: HashMap::new(),
+            rng,
+            acceleration: (0.0, 0.0, 0.0),
+            gps_dropout: false,
+        }
+    }
+
+    pub fn state(&self) -> &SimState {
+        &self.state
+    }
+
+    pub fn config(&self) -> &SimConfig {
+        &self.config
+    }
+
+    pub fn set_wind(&mut self, wind: Wind) {
+        self.config.wind = wind;
+    }
+
+    // While set, GPS reports no fix
+    pub fn set_gps_dropout(&mut self, dropout: bool) {
+        self.gps_dropout = dropout;
+    }
+
+    pub fn apply_action(&mut self, action: &BlimpAction) {
+        match action {
+            BlimpAction::SetMotor { motor, speed } => {
+                self.motor_speeds.insert(*motor, *speed);
+            }
+            BlimpAction::SetServo { servo, location } => {
+                self.servo_locations.insert(*servo, *location);
+            }
+            BlimpAction::SendMsg(_) => {}
+        }
+    }
+
+    // Advances physics by dt seconds, returns sensor events sampled meanwhile
+    pub fn step(&mut self, dt: f64) -> Vec<BlimpEvent> {
+        self.integrate(dt);
+        let mut events = Vec::new();
+        let t = self.state.time;
+        if t >= self.next_imu {
+            self.next_imu += 1.0 / self.config.imu_rate;
+            events.extend(self.imu_events());
+        }
+        if t >= self.next_baro {
+            self.next_baro += 1.0 / self.config.baro_rate;
+            events.push(self.baro_event());
+        }
+        if t >= self.next_gps {
+            self.next_gps += 1.0 / self.config.gps_rate;
+            events.push(self.gps_event());
+        }
+        events
+    }
+
+    fn integrate(&mut self, dt: f64) {
+        let c = &self.config;
+        let s = &self.state;
+
+        // Thrust in body frame (forward, right, up) and torque around vertical axis
+        let (mut thrust_f, mut thrust_r, mut thrust_u, mut torque) = (0.0, 0.0, 0.0, 0.0);
+        for m in &c.motors {
+            let thrust =
+                self.motor_speeds.get(&m.motor).copied().unwrap_or(0) as f64 * c.thrust_per_unit;
+            let servo_angle = |servo: Option<u8>| {
+                servo
+                    .and_then(|servo| self.servo_locations.get(&servo))
+                    .map_or(0.0, |&loc| loc as f64 * c.servo_rad_per_unit)
+            };
+            let tilt = servo_angle(m.tilt_servo);
+            let swivel = servo_angle(m.swivel_servo);
+            let f = thrust * tilt.cos() * swivel.cos();
+            let r = thrust * tilt.cos() * swivel.sin();
+            thrust_f += f;
+            thrust_r += r;
+            thrust_u += thrust * tilt.sin();
+            // Clockwise seen from above is positive
+            torque += m.forward_offset * r - m.right_offset * f;
+        }
+
+        let (sin_h, cos_h) = s.heading.sin_cos();
+        let thrust_n = thrust_f * cos_h - thrust_r * sin_h;
+        let thrust_e = thrust_f * sin_h + thrust_r * cos_h;
+
+        let gust = if c.wind.gust_period > 0.0 {
+            c.wind.gust_amplitude * (std::f64::consts::TAU * s.time / c.wind.gust_period).sin()
+        } else {
+            0.0
+        };
+        let wind_speed = c.wind.north.hypot(c.wind.east);
+        let (wind_n, wind_e) = if wind_speed > 0.0 {
+            let k = (wind_speed + gust) / wind_speed;
+            (c.wind.north * k, c.wind.east * k)
+        } else {
+            (0.0, 0.0)
+        };
+        let (vn, ve, vu) = s.velocity;
+        let (air_n, air_e) = (vn - wind_n, ve - wind_e);
+        let air_speed = air_n.hypot(air_e);
+        let drag_n = -c.drag_horizontal * air_n * air_speed;
+        let drag_e = -c.drag_horizontal * air_e * air_speed;
+        let drag_u = -c.drag_vertical * vu * vu.abs();
+
+        let accel = (
+            (thrust_n + drag_n) / c.mass,
+            (thrust_e + drag_e) / c.mass,
+            (thrust_u + c.net_buoyancy + drag_u) / c.mass,
+        );
+        let yaw_accel = (torque - c.yaw_damping * s.yaw_rate) / c.yaw_inertia;
+
+        let s = &mut self.state;
+        s.velocity = (vn + accel.0 * dt, ve + accel.1 * dt, vu + accel.2 * dt);
+        s.north += s.velocity.0 * dt;
+        s.east += s.velocity.1 * dt;
+        s.altitude += s.velocity.2 * dt;
+        s.yaw_rate += yaw_accel * dt;
+        s.heading = (s.heading + s.yaw_rate * dt).rem_euclid(std::f64::consts::TAU);
+        s.time += dt;
+        self.acceleration = accel;
+
+        // Resting on the ground
+        if s.altitude <= 0.0 {
+            s.altitude = 0.0;
+            s.velocity = (0.0, 0.0, s.velocity.2.max(0.0));
+            self.acceleration = (0.0, 0.0, 0.0);
+        }
+    }
+
+    fn imu_events(&mut self) -> Vec<BlimpEvent> {
+        let (sin_h, cos_h) = self.state.heading.sin_cos();
+        let (an, ae, au) = self.acceleration;
+        // Body frame is forward-right-down, accelerometer measures specific force
+        let accel = Vector3::new(
+            an * cos_h + ae * sin_h,
+            -an * sin_h + ae * cos_h,
+            -au - GRAVITY,
+        );
+        let gyro = Vector3::new(
+            self.rng.gaussian() * self.config.gyro_noise,
+            self.rng.gaussian() * self.config.gyro_noise,
+            self.state.yaw_rate + self.rng.gaussian() * self.config.gyro_noise,
+        );
+        let mag = Vector3::new(MAG_FIELD.0 * cos_h, -MAG_FIELD.0 * sin_h, MAG_FIELD.1);
+        vec![
+            BlimpEvent::SensorDataVec3(SensorType::Accelerometer, accel),
+            BlimpEvent::SensorDataVec3(SensorType::Magnetometer, mag),
+            BlimpEvent::SensorDataVec3(SensorType::Gyroscope, gyro),
+        ]
+    }
+
+    fn baro_event(&mut self) -> BlimpEvent {
+        let altitude = self.config.origin.2
+            + self.state.altitude
+            + self.rng.gaussian() * self.config.baro_noise;
+        let pressure = STANDARD_PRESSURE * (-altitude / (BARO_COEF * STANDARD_TEMPERATURE)).exp();
+        BlimpEvent::SensorDataF64(SensorType::Barometer, pressure)
+    }
+
+    fn gps_event(&mut self) -> BlimpEvent {
+        let (lat0, lon0, alt0) = self.config.origin;
+        let noise = self.config.gps_noise;
+        let north = self.state.north + self.rng.gaussian() * noise;
+        let east = self.state.east + self.rng.gaussian() * noise;
+        let latitude = lat0 + (north / EARTH_RADIUS).to_degrees();
+        let longitude = lon0 + (east / (EARTH_RADIUS * lat0.to_radians().cos())).to_degrees();
+        BlimpEvent::GpsFix(GpsFix {
+            latitude,
+            longitude,
+            altitude: alt0 + self.state.altitude + self.rng.gaussian() * noise * 2.0,
+            quality: if self.gps_dropout {
+                GpsFixQuality::NoFix
+            } else {
+                GpsFixQuality::Fix3D
+            },
+            satellites: if self.gps_dropout { 0 } else { 9 },
+            hdop: 1.0,
+            timestamp: (self.state.time * 1000.0) as u64,
+        })
+    }
+}
+
+// Closed loop of BlimpMainAlgo and Simulator on a shared mock clock, with a simulated ground
+// link in place of the radio
+pub struct Simulation {
+    pub algo: BlimpMainAlgo,
+    pub sim: Simulator,
+    clock: Arc<MockClock>,
+    downlink: Vec<MessageB2G>,
+    decoder: FrameDecoder,            // Ground side of downlink
+    link: ReliableSender<MessageG2B>, // Ground side of uplink
+    link_down: bool,
+    events: Vec<BlimpEvent>, // Queued for next tick, e.g. sensor faults
+    auth_key: Vec<u8>,
+    session: u64,               // Ground station's, sent in its Hello
+    blimp_session: Option<u64>, // Learned from the blimp's Hello, uplink is signed for it
+}
+
+impl Simulation {
+    // Key authenticating uplink messages, must be the one the algorithm was created with
+    pub fn new(mut algo: BlimpMainAlgo, sim: Simulator, auth_key: &[u8]) -> Self {
+        let clock = Arc::new(MockClock::new(Duration::from_secs_f64(sim.state().time)));
+        algo.set_clock(clock.clone());
+        let mut simulation = Self {
+            algo,
+            sim,
+            clock,
+            downlink: Vec::new(),
+            decoder: FrameDecoder::new(),
+            link: ReliableSender::new(RetransmitConfig::default()),
+            link_down: false,
+            events: Vec::new(),
+            auth_key: auth_key.to_vec(),
+            session: new_session_id(),
+            blimp_session: None,
+        };
+        // Ground station introduces itself first thing. Hello can't be signed yet, blimp answers
+        // with its session and the signed Hello is queued for the first tick.
+        simulation.uplink(&MessageG2B::Hello(Hello::current(simulation.session)));
+        let mut actions = Vec::new();
+        for ev in std::mem::take(&mut simulation.events) {
+            block_on(simulation.algo.handle_event(&ev, &mut actions));
+        }
+        simulation.route(actions);
+        simulation
+    }
+
+    pub fn time(&self) -> f64 {
+        self.sim.state().time
+    }
+
+    // While set, everything sent in either direction is lost
+    pub fn set_link_down(&mut self, down: bool) {
+        self.link_down = down;
+    }
+
+    // Message from ground, delivered on next tick unless the link is down
+    pub fn uplink(&mut self, msg: &MessageG2B) {
+        let now = Duration::from_secs_f64(self.time());
+        let bytes = self.link.send(msg.clone(), msg.reliable(), now);
+        if !self.link_down {
+            self.events.push(BlimpEvent::GetMsg(bytes));
+        }
+    }
+
+    // Extra event delivered on next tick, e.g. an injected sensor fault
+    pub fn inject_event(&mut self, ev: BlimpEvent) {
+        self.events.push(ev);
+    }
+
+    // Messages sent to ground since last call
+    pub fn take_downlink(&mut self) -> Vec<MessageB2G> {
+        std::mem::take(&mut self.downlink)
+    }
+
+    // Advances everything by dt seconds - physics, sensors, then one algorithm step.
+    // Returns actuator actions the algorithm emitted.
+    pub fn tick(&mut self, dt: f64) -> Vec<BlimpAction> {
+        let mut events = self.sim.step(dt);
+        events.append(&mut self.events);
+        let now = Duration::from_secs_f64(self.sim.state().time);
+        self.clock.set(now);
+        let (retransmits, _failed) = self.link.poll(now);
+        if !self.link_down {
+            events.extend(retransmits.into_iter().map(BlimpEvent::GetMsg));
+        }
+        let mut actions = Vec::new();
+        for ev in &events {
+            block_on(self.algo.handle_event(ev, &mut actions));
+        }
+        block_on(self.algo.step(&mut actions));
+        self.route(actions)
+    }
+
+    // Passes messages to ground and actuator actions to physics, returns the latter
+    fn route(&mut self, actions: Vec<BlimpAction>) -> Vec<BlimpAction> {
+        let mut actuator_actions = Vec::new();
+        for action in actions {
+            match &action {
+                BlimpAction::SendMsg(_) if self.link_down => {}
+                BlimpAction::SendMsg(bytes) => {
+                    for payload in self.decoder.push(bytes).into_iter().flatten() {
+                        if let Ok(msg) = postcard::from_bytes::<MessageB2G>(&payload) {
+                            match msg {
+                                MessageB2G::Ack(seq) => {
+                                    self.link.ack(seq);
+                                }
+                                // Ground station answers the blimp's link quality pings
+                                MessageB2G::Ping(id) => self.uplink(&MessageG2B::Pong(id)),
+                                // New blimp session, e.g. after a reboot
+                                MessageB2G::Hello(ref hello)
+                                    if self.blimp_session != Some(hello.session) =>
+                                {
+                                    self.blimp_session = Some(hello.session);
+                                    let signer = MessageSigner::new(
+                                        &self.auth_key,
+                                        hello.session,
+                                        hello.next_counter,
+                                    );
+                                    self.link.set_signer(Some(signer));
+                                    self.uplink(&MessageG2B::Hello(Hello::current(self.session)));
+                                }
+                                _ => {}
+                            }
+                            self.downlink.push(msg);
+                        }
+                    }
+                }
+                _ => {
+                    self.sim.apply_action(&action);
+                    actuator_actions.push(action);
+                }
+            }
+        }
+        actuator_actions
+    }
+}
+
+// Deterministic xorshift generator, so simulations are reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn uniform(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Standard normal, Box-Muller transform
+    fn gaussian(&mut self) -> f64 {
+        let u1 = self.uniform().max(f64::MIN_POSITIVE);
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f64 = 0.02;
+    const KEY: &[u8] = b"key";
+
+    fn airborne(altitude: f64) -> Simulation {
+        let sim = Simulator::new(
+            SimConfig::default(),
+            SimState {
+                altitude,
+                ..Default::default()
+            },
+        );
+        Simulation::new(BlimpMainAlgo::new(KEY), sim, KEY)
+    }
+
+    fn run(simulation: &mut Simulation, seconds: f64) {
+        for _ in 0..(seconds / DT) as usize {
+            simulation.tick(DT);
+        }
+    }
+
+    fn arm(simulation: &mut Simulation) {
+        simulation.uplink(&MessageG2B::Control(Controls {
+            throttle: 0,
+            elevation: 0,
+            yaw: 0,
+        }));
+        simulation.uplink(&MessageG2B::Arm);
+        simulation.tick(DT);
+        assert!(simulation.algo.armed());
+    }
+
+    #[test]
+    fn sinks_without_control() {
+        let mut simulation = airborne(10.0);
+        run(&mut simulation, 20.0);
+        assert!(simulation.sim.state().altitude < 9.0);
+    }
+
+    #[test]
+    fn altitude_hold_keeps_altitude() {
+        let mut simulation = airborne(10.0);
+        run(&mut simulation, 1.0);
+        arm(&mut simulation);
+        simulation.uplink(&MessageG2B::SetFlightMode(FlightMode::StabilizeAttiAlti));
+        for _ in 0..(60.0 / DT) as usize {
+            // Keep the link alive
+            simulation.uplink(&MessageG2B::Ping(0));
+            simulation.tick(DT);
+        }
+        let altitude = simulation.sim.state().altitude;
+        assert!((altitude - 10.0).abs() < 1.0, "altitude {altitude}");
+    }
+
+    #[test]
+    fn manual_throttle_flies_forward() {
+        let mut simulation = airborne(10.0);
+        arm(&mut simulation);
+        simulation.uplink(&MessageG2B::Control(Controls {
+            throttle: 500,
+            elevation: 0,
+            yaw: 0,
+        }));
+        run(&mut simulation, 1.5);
+        assert!(simulation.sim.state().north > 0.5);
+    }
+}
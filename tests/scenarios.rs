@@ -0,0 +1,24 @@
+// Runs every scenario in scenarios/ and checks its expectations
+
+use blimp_onboard_software::obsw_scenario::*;
+
+#[test]
+fn scenarios_pass() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let scenario = Scenario::load(path).unwrap();
+        let result = run_scenario(&scenario).unwrap();
+        let name = path.file_name().unwrap().to_string_lossy();
+        failures.extend(result.failures.iter().map(|f| format!("{name}: {f}")));
+    }
+    assert!(failures.is_empty(), "{failures:#?}");
+}